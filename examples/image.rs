@@ -1,16 +1,8 @@
-// use crate::unique::time_uuid;
-// use crate::error::Error;
-
-use actix_web::{HttpResponse, web};
-use actix_multipart::{Multipart, Field};
-use futures::{StreamExt, TryStreamExt};
-use std::{io::Write, path::Path};
-use serde::{Deserialize, Serialize};
-use image::{self, imageops::{self, FilterType}};
-
-static PATH: &str = "/home/sankar/Pictures/sample.jpg";
+use std::env;
 
 fn main() -> std::io::Result<()> {
-    let new_image = image::open(PATH).unwrap(); 
+    let path = env::args().nth(1).expect("usage: image <path-to-image>");
+    let new_image = image::open(&path).unwrap();
+    println!("{}: {}x{}", path, new_image.width(), new_image.height());
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,107 @@
+//! Compares the two resize engines on real photos:
+//!
+//!     cargo run --release --example resize_bench -- photo.jpg [more.jpg ...]
+//!
+//! Each photo, ideally a 12MP (4000x3000) camera shot, is scaled to the
+//! default variant sizes with every filter by both engines. Prints the
+//! best of `LILY_BENCH_RUNS` (default 5) runs of each and how far the
+//! fast engine's pixels are from those of `imageops`. Without a photo it
+//! uses a generated 4000x3000 image, so runs on different machines have
+//! the same input; the time taken does not depend on the pixel values.
+
+#[path = "../src/resize.rs"]
+mod resize;
+
+use std::env;
+use std::time::{Duration, Instant};
+use image::RgbaImage;
+use image::imageops::{self, FilterType};
+
+/// Bounds of the default `lg`, `md` and `sm` profiles.
+const SIZES: [(u32, u32); 3] = [(1920, 1080), (720, 576), (320, 240)];
+
+const FILTERS: [(&str, FilterType); 4] = [
+    ("triangle", FilterType::Triangle),
+    ("catmullrom", FilterType::CatmullRom),
+    ("gaussian", FilterType::Gaussian),
+    ("lanczos3", FilterType::Lanczos3),
+];
+
+/// Size that fits `width` x `height` inside `bounds`, keeping the ratio.
+fn fit(width: u32, height: u32, bounds: (u32, u32)) -> (u32, u32) {
+    let scale = f64::min(bounds.0 as f64 / width as f64, bounds.1 as f64 / height as f64).min(1.0);
+    let scaled = |n: u32| ((n as f64 * scale).round() as u32).max(1);
+    (scaled(width), scaled(height))
+}
+
+fn best_of<F: FnMut() -> RgbaImage>(runs: u32, mut f: F) -> (Duration, RgbaImage) {
+    let mut best = Duration::MAX;
+    let mut img = None;
+    for _ in 0..runs {
+        let start = Instant::now();
+        img = Some(f());
+        best = best.min(start.elapsed());
+    }
+    (best, img.expect("at least one run"))
+}
+
+/// A 4000x3000 stand-in for a photo: gradients with deterministic noise.
+fn generated() -> RgbaImage {
+    let mut seed = 0x2545_f491_u32;
+    RgbaImage::from_fn(4000, 3000, |x, y| {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        let noise = (seed & 0x1f) as u8;
+        image::Rgba([(x / 16) as u8 ^ noise, (y / 12) as u8 ^ noise, ((x + y) / 28) as u8, 255])
+    })
+}
+
+/// Largest and mean absolute difference over all channels.
+fn difference(a: &RgbaImage, b: &RgbaImage) -> (u8, f64) {
+    let mut max = 0;
+    let mut sum = 0u64;
+    for (x, y) in a.as_raw().iter().zip(b.as_raw()) {
+        let d = x.abs_diff(*y);
+        max = max.max(d);
+        sum += u64::from(d);
+    }
+    (max, sum as f64 / a.as_raw().len() as f64)
+}
+
+fn main() {
+    let paths: Vec<String> = env::args().skip(1).collect();
+    let runs = env::var("LILY_BENCH_RUNS").ok().and_then(|n| n.parse().ok()).unwrap_or(5).max(1);
+
+    let inputs: Vec<(String, RgbaImage)> = if paths.is_empty() {
+        vec![("generated".to_owned(), generated())]
+    } else {
+        paths
+            .iter()
+            .map(|path| (path.clone(), image::open(path).unwrap_or_else(|e| panic!("{}: {}", path, e)).to_rgba8()))
+            .collect()
+    };
+    for (path, src) in inputs {
+        let (width, height) = src.dimensions();
+        println!("{}: {}x{}, {:.1} MP, best of {}", path, width, height, f64::from(width * height) / 1e6, runs);
+        println!("{:<11} {:>9} {:>11} {:>9} {:>8} {:>9} {:>9}", "filter", "size", "imageops", "fast", "speedup", "max diff", "mean diff");
+        for (name, filter) in FILTERS {
+            for bounds in SIZES {
+                let (w, h) = fit(width, height, bounds);
+                let (slow, expected) = best_of(runs, || imageops::resize(&src, w, h, filter));
+                let (fast, actual) = best_of(runs, || resize::resize(&src, w, h, filter));
+                let (max, mean) = difference(&expected, &actual);
+                println!(
+                    "{:<11} {:>9} {:>9.1}ms {:>7.1}ms {:>7.1}x {:>9} {:>9.3}",
+                    name,
+                    format!("{}x{}", w, h),
+                    slow.as_secs_f64() * 1e3,
+                    fast.as_secs_f64() * 1e3,
+                    slow.as_secs_f64() / fast.as_secs_f64(),
+                    max,
+                    mean,
+                );
+            }
+        }
+    }
+}
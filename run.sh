@@ -1,3 +1,3 @@
 #!/bin/bash
 
-HOST=192.168.95.133 PORT=7600 ./target/release/lily-image
\ No newline at end of file
+LILY_CONFIG=${LILY_CONFIG:-lily.toml} LILY_SERVER_HOST=192.168.95.133 LILY_SERVER_PORT=7600 ./target/release/lily-image
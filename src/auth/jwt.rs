@@ -0,0 +1,225 @@
+use super::{unauthenticated, Role, AUTHUSER};
+use crate::config::JwtConfig;
+use crate::error::Error;
+
+use std::fs;
+use anyhow::Context;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+/// Claims mapped onto `AUTHUSER`. `sub` carries the user id.
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    fname: String,
+    #[serde(default)]
+    lname: String,
+    #[serde(default)]
+    email: String,
+    #[serde(default)]
+    role: Role,
+    /// Checked here rather than by `Validation`, which would also refuse
+    /// tokens without one.
+    #[serde(default)]
+    nbf: Option<i64>,
+}
+
+/// Verifies `Authorization: Bearer` tokens against the keys in
+/// `[auth.jwt]`. With no keys configured every token is rejected.
+pub struct JwtVerifier {
+    keys: Vec<(Algorithm, DecodingKey<'static>)>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: u64,
+}
+
+fn read_pem(name: &str, path: &std::path::Path) -> anyhow::Result<Vec<u8>> {
+    fs::read(path).with_context(|| format!("could not read {} ({})", name, path.display()))
+}
+
+impl JwtVerifier {
+    pub fn from_config(config: &JwtConfig) -> anyhow::Result<Self> {
+        let mut keys = vec![];
+        if let Some(secret) = &config.hs256_secret {
+            keys.push((Algorithm::HS256, DecodingKey::from_secret(secret.as_bytes()).into_static()));
+        }
+        if let Some(path) = &config.rs256_public_key {
+            let pem = read_pem("auth.jwt.rs256_public_key", path)?;
+            let key = DecodingKey::from_rsa_pem(&pem).context("auth.jwt.rs256_public_key is not an RSA public key")?;
+            keys.push((Algorithm::RS256, key.into_static()));
+        }
+        if let Some(path) = &config.es256_public_key {
+            let pem = read_pem("auth.jwt.es256_public_key", path)?;
+            let key = DecodingKey::from_ec_pem(&pem).context("auth.jwt.es256_public_key is not an EC public key")?;
+            keys.push((Algorithm::ES256, key.into_static()));
+        }
+        Ok(JwtVerifier {
+            keys,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            leeway: config.leeway,
+        })
+    }
+
+    /// Checks the signature, `exp`, `nbf` and the configured `iss`/`aud`.
+    /// Any failure is a 401; the reason is only logged.
+    pub fn verify(&self, token: &str) -> Result<AUTHUSER, Error> {
+        let rejected = |reason: &dyn std::fmt::Display| {
+            log::debug!("rejected bearer token: {}", reason);
+            unauthenticated()
+        };
+        let header = decode_header(token).map_err(|e| rejected(&e))?;
+        let (alg, key) = self
+            .keys
+            .iter()
+            .find(|(alg, _)| *alg == header.alg)
+            .ok_or_else(|| rejected(&format!("no key for {:?}", header.alg)))?;
+
+        let mut validation = Validation::new(*alg);
+        validation.leeway = self.leeway;
+        validation.iss = self.issuer.clone();
+        if let Some(audience) = &self.audience {
+            validation.set_audience(&[audience]);
+        }
+        let claims = decode::<Claims>(token, key, &validation).map_err(|e| rejected(&e))?.claims;
+        if let Some(nbf) = claims.nbf {
+            if nbf > chrono::Utc::now().timestamp().saturating_add_unsigned(self.leeway) {
+                return Err(rejected(&"token is not valid yet"));
+            }
+        }
+        let user_id = claims.sub.parse().map_err(|e| rejected(&e))?;
+        Ok(AUTHUSER {
+            userId: user_id,
+            fname: claims.fname,
+            lname: claims.lname,
+            email: claims.email,
+            role: claims.role,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Role;
+    use actix_web::http::StatusCode;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+
+    const SECRET: &str = "jwt-tests";
+    // key pairs generated with openssl for these tests only
+    const RS256_KEY: &[u8] = include_bytes!("testdata/rs256.key.pem");
+    const ES256_KEY: &[u8] = include_bytes!("testdata/es256.key.pem");
+
+    fn testdata(file: &str) -> std::path::PathBuf {
+        [env!("CARGO_MANIFEST_DIR"), "src/auth/testdata", file].iter().collect()
+    }
+
+    fn config() -> JwtConfig {
+        JwtConfig {
+            hs256_secret: Some(SECRET.to_owned()),
+            rs256_public_key: Some(testdata("rs256.pub.pem")),
+            es256_public_key: Some(testdata("es256.pub.pem")),
+            ..JwtConfig::default()
+        }
+    }
+
+    fn verifier(config: &JwtConfig) -> JwtVerifier {
+        JwtVerifier::from_config(config).unwrap()
+    }
+
+    fn now() -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+
+    fn sign(alg: Algorithm, key: &EncodingKey, claims: serde_json::Value) -> String {
+        encode(&Header::new(alg), &claims, key).unwrap()
+    }
+
+    fn hs256(claims: serde_json::Value) -> String {
+        sign(Algorithm::HS256, &EncodingKey::from_secret(SECRET.as_bytes()), claims)
+    }
+
+    fn status(result: Result<AUTHUSER, Error>) -> StatusCode {
+        result.map(|user| user.userId).unwrap_err().get_status()
+    }
+
+    #[test]
+    fn accepts_each_configured_algorithm() {
+        let verifier = verifier(&config());
+        let claims = json!({ "sub": "12", "exp": now() + 600, "email": "a@b.c", "role": "admin" });
+        let tokens = [
+            hs256(claims.clone()),
+            sign(Algorithm::RS256, &EncodingKey::from_rsa_pem(RS256_KEY).unwrap(), claims.clone()),
+            sign(Algorithm::ES256, &EncodingKey::from_ec_pem(ES256_KEY).unwrap(), claims),
+        ];
+        for token in tokens {
+            let user = verifier.verify(&token).unwrap();
+            assert_eq!(user.userId, 12);
+            assert_eq!(user.email, "a@b.c");
+            assert_eq!(user.role, Role::Admin);
+        }
+        let user = verifier.verify(&hs256(json!({ "sub": "-3", "exp": now() + 600 }))).unwrap();
+        assert_eq!((user.userId, user.role), (-3, Role::User));
+    }
+
+    #[test]
+    fn refuses_algorithms_without_a_key() {
+        let claims = json!({ "sub": "12", "exp": now() + 600 });
+        let rs256 = sign(Algorithm::RS256, &EncodingKey::from_rsa_pem(RS256_KEY).unwrap(), claims.clone());
+        let hs_only = verifier(&JwtConfig { hs256_secret: Some(SECRET.to_owned()), ..JwtConfig::default() });
+        assert_eq!(status(hs_only.verify(&rs256)), StatusCode::UNAUTHORIZED);
+        let hs384 = sign(Algorithm::HS384, &EncodingKey::from_secret(SECRET.as_bytes()), claims.clone());
+        assert_eq!(status(hs_only.verify(&hs384)), StatusCode::UNAUTHORIZED);
+
+        // an HMAC made with the public key must not pass for an RSA key
+        let public_key = std::fs::read(testdata("rs256.pub.pem")).unwrap();
+        let confused = sign(Algorithm::HS256, &EncodingKey::from_secret(&public_key), claims.clone());
+        let rs_only = verifier(&JwtConfig { rs256_public_key: Some(testdata("rs256.pub.pem")), ..JwtConfig::default() });
+        assert_eq!(status(rs_only.verify(&confused)), StatusCode::UNAUTHORIZED);
+
+        let none = verifier(&JwtConfig::default());
+        assert_eq!(status(none.verify(&hs256(claims))), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(none.verify("not.a.token")), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn refuses_expired_and_early_tokens_beyond_the_leeway() {
+        let verifier = verifier(&config());
+        assert!(verifier.verify(&hs256(json!({ "sub": "12", "exp": now() - 30 }))).is_ok());
+        assert_eq!(status(verifier.verify(&hs256(json!({ "sub": "12", "exp": now() - 120 })))), StatusCode::UNAUTHORIZED);
+        assert_eq!(status(verifier.verify(&hs256(json!({ "sub": "12" })))), StatusCode::UNAUTHORIZED);
+        assert!(verifier.verify(&hs256(json!({ "sub": "12", "exp": now() + 600, "nbf": now() + 30 }))).is_ok());
+        let claims = json!({ "sub": "12", "exp": now() + 600, "nbf": now() + 300 });
+        assert_eq!(status(verifier.verify(&hs256(claims))), StatusCode::UNAUTHORIZED);
+    }
+
+    #[test]
+    fn checks_issuer_and_audience_when_configured() {
+        let verifier = verifier(&JwtConfig {
+            issuer: Some("lily-web".to_owned()),
+            audience: Some("lily-image".to_owned()),
+            ..config()
+        });
+        let exp = now() + 600;
+        assert!(verifier.verify(&hs256(json!({ "sub": "12", "exp": exp, "iss": "lily-web", "aud": "lily-image" }))).is_ok());
+        for claims in [
+            json!({ "sub": "12", "exp": exp, "iss": "someone", "aud": "lily-image" }),
+            json!({ "sub": "12", "exp": exp, "iss": "lily-web", "aud": "someone" }),
+            json!({ "sub": "12", "exp": exp, "aud": "lily-image" }),
+            json!({ "sub": "12", "exp": exp, "iss": "lily-web" }),
+        ] {
+            assert_eq!(status(verifier.verify(&hs256(claims.clone()))), StatusCode::UNAUTHORIZED, "{}", claims);
+        }
+    }
+
+    #[test]
+    fn refuses_subjects_that_are_not_user_ids() {
+        let verifier = verifier(&config());
+        for sub in [json!("alice"), json!("12abc"), json!(""), json!("3000000000"), json!(12)] {
+            let token = hs256(json!({ "sub": sub, "exp": now() + 600 }));
+            assert_eq!(status(verifier.verify(&token)), StatusCode::UNAUTHORIZED, "{}", sub);
+        }
+    }
+}
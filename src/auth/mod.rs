@@ -1,36 +1,86 @@
+mod jwt;
+
+pub use self::jwt::JwtVerifier;
+
 use serde::{Serialize, Deserialize };
 use actix_session::Session;
-use crate::error::Error;
+use actix_web::{HttpMessage, HttpRequest};
+use crate::error::{Error, ErrorKind};
 
+/// Session key under which lily-web stores the signed-in user as JSON.
+pub static AUTH_USER: &str = "AUTH_USER";
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    /// May act on other users' images; every such action is audit-logged.
+    Admin,
+}
 
-#[derive(Deserialize, Serialize)]
-#[allow(non_snake_case)]
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[allow(non_snake_case, clippy::upper_case_acronyms)]
 pub struct AUTHUSER {
     pub userId: i32,
     pub fname: String,
     pub lname: String,
     pub email: String,
+    #[serde(default)]
+    pub role: Role,
 }
 
-#[allow(dead_code)]
-pub fn auth_session(session: &Session) -> Result<AUTHUSER, Error>  {
-    let auth_user = session.get::<String>("AUTH_USER")?;
-    match auth_user {
-        Some(auth_user) => Ok(serde_json::from_str(&auth_user)?),
-        None => return Err(Error::from("UN_AUTHENTICATED_USER").into())
+impl AUTHUSER {
+    /// The user `action` is performed for: `requested` if given, else the
+    /// signed-in user. Anyone but an admin asking for another user gets a
+    /// 403.
+    pub fn owner(&self, requested: Option<i32>, action: &str) -> Result<i32, Error> {
+        match requested {
+            None => Ok(self.userId),
+            Some(user_id) if user_id == self.userId => Ok(user_id),
+            Some(user_id) if self.role == Role::Admin => {
+                log::info!(target: "audit", "admin {} ({}) {} on behalf of user {}", self.userId, self.email, action, user_id);
+                Ok(user_id)
+            }
+            Some(_) => Err(Error::new(ErrorKind::Forbidden, "not allowed to act for this user")),
+        }
+    }
+
+    /// For admin-only actions on `user_id`'s images: a 403 for anyone
+    /// else, an audit log entry for admins.
+    pub fn admin(&self, user_id: i32, action: &str) -> Result<(), Error> {
+        if self.role != Role::Admin {
+            return Err(Error::new(ErrorKind::Forbidden, "admin only"));
+        }
+        log::info!(target: "audit", "admin {} ({}) {} for user {}", self.userId, self.email, action, user_id);
+        Ok(())
     }
 }
 
+pub(crate) fn unauthenticated() -> Error {
+    Error::new(ErrorKind::Unauthenticated, "UN_AUTHENTICATED_USER")
+}
+
 pub trait AuthSession {
     fn user_info(&self) -> Result<AUTHUSER, Error>;
 }
 
 impl  AuthSession for Session {
+    /// Reads the user out of the session; a missing or unreadable entry
+    /// is a 401.
     fn user_info(&self) -> Result<AUTHUSER, Error> {
-        let auth_user = self.get::<String>("AUTH_USER")?;
+        let auth_user = self.get::<String>(AUTH_USER).map_err(|_| unauthenticated())?;
         match auth_user {
-            Some(auth_user) => Ok(serde_json::from_str(&auth_user)?),
-            None => return Err(Error::from("UN_AUTHENTICATED_USER").into())
+            Some(auth_user) => serde_json::from_str(&auth_user).map_err(|_| unauthenticated()),
+            None => Err(unauthenticated())
         }
     }
-}
\ No newline at end of file
+}
+
+impl AuthSession for HttpRequest {
+    /// The user resolved by the `Authentication` middleware. Handlers
+    /// outside an authenticated scope always get a 401.
+    fn user_info(&self) -> Result<AUTHUSER, Error> {
+        self.extensions().get::<AUTHUSER>().cloned().ok_or_else(unauthenticated)
+    }
+}
@@ -0,0 +1,240 @@
+use crate::catalog::{ImageCatalog, ImageStatus, VariantRecord};
+use crate::config::AvifConfig;
+use crate::error::Error;
+use crate::storage::Storage;
+use crate::upload::variant_file_name;
+use crate::workers::WorkerPool;
+
+use std::sync::Arc;
+use actix_web::http::StatusCode;
+use actix_web::web;
+use bytes::Bytes;
+use image::RgbaImage;
+use ravif::{Img, RGBA8};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+pub static AVIF_EXT: &str = "avif";
+
+/// A scaled variant waiting to be encoded as AVIF.
+pub struct PendingVariant {
+    pub name: String,
+    pub suffix: String,
+    pub density: u32,
+    pub image: RgbaImage,
+}
+
+fn encode(img: &RgbaImage, config: AvifConfig) -> Result<Vec<u8>, Error> {
+    let pixels: Vec<RGBA8> = img.pixels().map(|p| RGBA8::new(p[0], p[1], p[2], p[3])).collect();
+    let encoded = ravif::Encoder::new()
+        .with_quality(f32::from(config.quality))
+        .with_speed(config.speed)
+        .encode_rgba(Img::new(&pixels[..], img.width() as usize, img.height() as usize))
+        .map_err(|e| Error::from(format!("could not encode AVIF variant: {}", e)))?;
+    Ok(encoded.avif_file)
+}
+
+/// Encodes `pending` one by one on `pool` and adds each file to the
+/// image's catalog record. If the image was deleted or replaced in the
+/// meantime, the file is removed again.
+async fn store(storage: &dyn Storage, catalog: &dyn ImageCatalog, pool: &WorkerPool, config: AvifConfig, user_id: i32, image_id: Uuid, pending: Vec<PendingVariant>) -> Result<(), Error> {
+    let img_name = image_id.to_string();
+    let mut pending = pending.into_iter();
+    while let Some(variant) = pending.next() {
+        let PendingVariant { name, suffix, density, image } = variant;
+        let (width, height) = image.dimensions();
+        let data = match pool.run(move || encode(&image, config)).await {
+            Ok(data) => data,
+            Err(e) => {
+                if e.get_status() == StatusCode::SERVICE_UNAVAILABLE {
+                    let dropped: Vec<String> = std::iter::once(suffix).chain(pending.map(|p| p.suffix)).collect();
+                    remove_stale(storage, catalog, user_id, image_id, &dropped).await?;
+                }
+                return Err(e);
+            }
+        };
+        let key = format!("{}/{}", user_id, variant_file_name(&img_name, &suffix, AVIF_EXT));
+        let hash = hex::encode(Sha256::digest(&data));
+        let bytes = storage.replace_bytes(&key, Bytes::from(data)).await?;
+        let variant = VariantRecord {
+            name,
+            suffix,
+            ext: AVIF_EXT.to_owned(),
+            density,
+            width,
+            height,
+            bytes,
+            hash,
+        };
+        // the record may have been replaced, reprocessed or deleted while
+        // this variant was encoded
+        let recorded = catalog
+            .modify(user_id, image_id, &mut |record| {
+                if record.status != ImageStatus::Active {
+                    return false;
+                }
+                record.variants.retain(|v| v.suffix != variant.suffix || v.ext != AVIF_EXT);
+                record.variants.push(variant.clone());
+                true
+            })
+            .await?;
+        if !recorded {
+            storage.delete(&key).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the AVIF files of `suffixes` left from before the image was
+/// reprocessed, once they are not going to be encoded again; they show
+/// the old crop. Serving falls back to the other formats.
+async fn remove_stale(storage: &dyn Storage, catalog: &dyn ImageCatalog, user_id: i32, image_id: Uuid, suffixes: &[String]) -> Result<(), Error> {
+    let stale = |v: &VariantRecord| v.ext == AVIF_EXT && suffixes.contains(&v.suffix);
+    let removed = catalog
+        .modify(user_id, image_id, &mut |record| {
+            if record.status != ImageStatus::Active || !record.variants.iter().any(stale) {
+                return false;
+            }
+            record.variants.retain(|v| !stale(v));
+            true
+        })
+        .await?;
+    if !removed {
+        return Ok(());
+    }
+    for suffix in suffixes {
+        let key = format!("{}/{}", user_id, variant_file_name(&image_id.to_string(), suffix, AVIF_EXT));
+        if storage.exists(&key).await? {
+            storage.delete(&key).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Encodes AVIF variants in the background, on a pool of its own so
+/// that slow encodings neither take threads from uploads nor pile up
+/// without bound.
+pub struct AvifEncoder {
+    pool: Arc<WorkerPool>,
+    config: AvifConfig,
+}
+
+impl AvifEncoder {
+    pub fn new(config: &AvifConfig) -> anyhow::Result<Self> {
+        Ok(AvifEncoder {
+            pool: Arc::new(WorkerPool::named("lily-avif", &config.workers())?),
+            config: *config,
+        })
+    }
+
+    /// Encodes `pending` in the background; the request does not wait.
+    /// When the pool's queue is full, the variants not encoded yet are
+    /// dropped; reprocessing the image tries them again.
+    pub fn spawn(&self, storage: web::Data<dyn Storage>, catalog: web::Data<dyn ImageCatalog>, user_id: i32, image_id: Uuid, pending: Vec<PendingVariant>) {
+        if pending.is_empty() {
+            return;
+        }
+        let pool = self.pool.clone();
+        let config = self.config;
+        actix_web::rt::spawn(async move {
+            match store(storage.as_ref(), catalog.as_ref(), &pool, config, user_id, image_id, pending).await {
+                Ok(()) => {}
+                Err(e) if e.get_status() == StatusCode::SERVICE_UNAVAILABLE => {
+                    log::warn!("AVIF variants of {} dropped, the encoder is busy", image_id);
+                }
+                Err(e) => log::error!("AVIF variants of {} failed: {}", image_id, e),
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::{EmbeddedCatalog, ImageRecord};
+    use crate::config::WorkersConfig;
+    use crate::storage::MemoryStorage;
+    use image::Rgba;
+
+    struct Setup {
+        storage: MemoryStorage,
+        catalog: EmbeddedCatalog,
+        pool: WorkerPool,
+        _dir: tempfile::TempDir,
+    }
+
+    fn setup() -> Setup {
+        let dir = tempfile::tempdir().unwrap();
+        Setup {
+            storage: MemoryStorage::default(),
+            catalog: EmbeddedCatalog::open(&dir.path().join("catalog")).unwrap(),
+            pool: WorkerPool::new(&WorkersConfig { threads: 1, queue: 1, retry_after: 1 }).unwrap(),
+            _dir: dir,
+        }
+    }
+
+    fn record(image_id: Uuid, status: ImageStatus) -> ImageRecord {
+        ImageRecord {
+            user_id: 12,
+            image_id,
+            ext: "jpg".to_owned(),
+            variants: vec![VariantRecord {
+                name: "md".to_owned(),
+                suffix: "720".to_owned(),
+                ext: String::new(),
+                density: 1,
+                width: 8,
+                height: 6,
+                bytes: 100,
+                hash: String::new(),
+            }],
+            content_hash: String::new(),
+            original_filename: "photo.jpg".to_owned(),
+            status,
+            crop: None,
+        }
+    }
+
+    async fn store_one(setup: &Setup, image_id: Uuid) -> String {
+        let pending = vec![PendingVariant {
+            name: "md".to_owned(),
+            suffix: "720".to_owned(),
+            density: 1,
+            image: RgbaImage::from_pixel(8, 6, Rgba([200, 120, 40, 255])),
+        }];
+        let config = AvifConfig { speed: 10, ..AvifConfig::default() };
+        store(&setup.storage, &setup.catalog, &setup.pool, config, 12, image_id, pending).await.unwrap();
+        format!("12/{}_720.avif", image_id)
+    }
+
+    #[actix_web::test]
+    async fn store_adds_the_file_to_the_record() {
+        let setup = setup();
+        let id = Uuid::new_v4();
+        setup.catalog.put(&record(id, ImageStatus::Active)).await.unwrap();
+        let key = store_one(&setup, id).await;
+
+        assert!(setup.storage.exists(&key).await.unwrap());
+        let stored = setup.catalog.get(12, id).await.unwrap().unwrap();
+        let exts: Vec<&str> = stored.variants.iter().map(|v| stored.variant_ext(v)).collect();
+        assert_eq!(exts, ["jpg", "avif"]);
+        // encoding again replaces the entry rather than adding another
+        store_one(&setup, id).await;
+        assert_eq!(setup.catalog.get(12, id).await.unwrap().unwrap().variants.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn store_does_not_bring_back_deleted_or_replaced_images() {
+        let setup = setup();
+        let deleted = Uuid::new_v4();
+        let key = store_one(&setup, deleted).await;
+        assert!(!setup.storage.exists(&key).await.unwrap());
+        assert!(setup.catalog.get(12, deleted).await.unwrap().is_none());
+
+        let replaced = Uuid::new_v4();
+        setup.catalog.put(&record(replaced, ImageStatus::Trashed)).await.unwrap();
+        let key = store_one(&setup, replaced).await;
+        assert!(!setup.storage.exists(&key).await.unwrap());
+        assert_eq!(setup.catalog.get(12, replaced).await.unwrap().unwrap().variants.len(), 1);
+    }
+}
@@ -0,0 +1,232 @@
+use super::{ImageCatalog, ImageRecord, RecordChange};
+use crate::error::Error;
+use crate::unique::time_ticks;
+
+use std::path::Path;
+use async_trait::async_trait;
+use uuid::Uuid;
+
+/// Image catalog in a local sled database, for deployments without a
+/// Scylla cluster. Rows are JSON-encoded `ImageRecord`s under
+/// `user_id ++ ticks ++ image_id`, so a user's images are contiguous and
+/// sorted by upload time like the Scylla clustering order.
+pub struct EmbeddedCatalog {
+    tree: sled::Tree,
+}
+
+impl EmbeddedCatalog {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let db = sled::open(path)?;
+        Ok(EmbeddedCatalog {
+            tree: db.open_tree("images")?,
+        })
+    }
+}
+
+fn user_prefix(user_id: i32) -> [u8; 4] {
+    // flip the sign bit so negative ids sort before positive ones
+    ((user_id as u32) ^ 0x8000_0000).to_be_bytes()
+}
+
+fn key(user_id: i32, image_id: &Uuid) -> Vec<u8> {
+    let mut key = Vec::with_capacity(28);
+    key.extend_from_slice(&user_prefix(user_id));
+    key.extend_from_slice(&time_ticks(image_id).unwrap_or_default().to_be_bytes());
+    key.extend_from_slice(image_id.as_bytes());
+    key
+}
+
+#[async_trait(?Send)]
+impl ImageCatalog for EmbeddedCatalog {
+    async fn put(&self, record: &ImageRecord) -> Result<(), Error> {
+        let value = serde_json::to_vec(record)?;
+        self.tree.insert(key(record.user_id, &record.image_id), value)?;
+        Ok(())
+    }
+
+    async fn get(&self, user_id: i32, image_id: Uuid) -> Result<Option<ImageRecord>, Error> {
+        match self.tree.get(key(user_id, &image_id))? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn list(&self, user_id: i32, before: Option<Uuid>, limit: usize) -> Result<Vec<ImageRecord>, Error> {
+        let start = user_prefix(user_id).to_vec();
+        let end = match before {
+            Some(before) => key(user_id, &before),
+            None => user_prefix(user_id.wrapping_add(1)).to_vec(),
+        };
+        let mut records = vec![];
+        let iter = if user_id == i32::MAX && before.is_none() {
+            self.tree.range(start..)
+        } else {
+            self.tree.range(start..end)
+        };
+        for entry in iter.rev().take(limit) {
+            let (_, value) = entry?;
+            records.push(serde_json::from_slice(&value)?);
+        }
+        Ok(records)
+    }
+
+    async fn delete(&self, user_id: i32, image_id: Uuid) -> Result<(), Error> {
+        self.tree.remove(key(user_id, &image_id))?;
+        Ok(())
+    }
+    async fn modify(&self, user_id: i32, image_id: Uuid, change: &mut RecordChange<'_>) -> Result<bool, Error> {
+        let key = key(user_id, &image_id);
+        loop {
+            let old = match self.tree.get(&key)? {
+                Some(old) => old,
+                None => return Ok(false),
+            };
+            let mut record: ImageRecord = serde_json::from_slice(&old)?;
+            if !change(&mut record) {
+                return Ok(false);
+            }
+            let new = serde_json::to_vec(&record)?;
+            if self.tree.compare_and_swap(&key, Some(old), Some(new))?.is_ok() {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::ImageStatus;
+    use uuid::v1::{Context, Timestamp};
+
+    fn catalog() -> (EmbeddedCatalog, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        (EmbeddedCatalog::open(&dir.path().join("catalog")).unwrap(), dir)
+    }
+
+    /// A v1 id made `secs` after 2024-01-01, on node `node`.
+    fn id_at(secs: u64, node: u8) -> Uuid {
+        let ts = Timestamp::from_unix(Context::new(0), 1_704_067_200 + secs, 0);
+        Uuid::new_v1(ts, &[node; 6]).unwrap()
+    }
+
+    fn record(user_id: i32, image_id: Uuid) -> ImageRecord {
+        ImageRecord {
+            user_id,
+            image_id,
+            ext: "jpg".to_owned(),
+            variants: vec![],
+            content_hash: String::new(),
+            original_filename: "photo.jpg".to_owned(),
+            status: ImageStatus::Active,
+            crop: None,
+        }
+    }
+
+    async fn ids(catalog: &EmbeddedCatalog, user_id: i32, before: Option<Uuid>, limit: usize) -> Vec<Uuid> {
+        let records = catalog.list(user_id, before, limit).await.unwrap();
+        assert!(records.iter().all(|r| r.user_id == user_id));
+        records.into_iter().map(|r| r.image_id).collect()
+    }
+
+    #[actix_web::test]
+    async fn lists_newest_first_whatever_the_insert_order() {
+        let (catalog, _dir) = catalog();
+        // the same second on two nodes, to check ties are ordered too
+        let ids_by_age = [id_at(5, 2), id_at(5, 1), id_at(4, 9), id_at(2, 0), id_at(1, 0)];
+        for i in [2, 4, 0, 3, 1] {
+            catalog.put(&record(12, ids_by_age[i])).await.unwrap();
+        }
+        assert_eq!(ids(&catalog, 12, None, 10).await, ids_by_age);
+        assert_eq!(ids(&catalog, 12, None, 2).await, ids_by_age[..2]);
+        assert!(ids(&catalog, 12, None, 0).await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn cursor_continues_where_the_page_ended() {
+        let (catalog, _dir) = catalog();
+        for secs in 1..=5 {
+            catalog.put(&record(12, id_at(secs, 0))).await.unwrap();
+        }
+        let mut seen = vec![];
+        let mut before = None;
+        loop {
+            let page = ids(&catalog, 12, before, 2).await;
+            match page.last() {
+                Some(last) => before = Some(*last),
+                None => break,
+            }
+            seen.extend(page);
+        }
+        assert_eq!(seen, (1..=5).rev().map(|secs| id_at(secs, 0)).collect::<Vec<_>>());
+        // a cursor that is not in the catalog still splits by time
+        assert_eq!(ids(&catalog, 12, Some(id_at(3, 7)), 10).await, [id_at(3, 0), id_at(2, 0), id_at(1, 0)]);
+    }
+
+    #[actix_web::test]
+    async fn users_do_not_see_each_others_images() {
+        let (catalog, _dir) = catalog();
+        let users = [i32::MIN, -2, -1, 0, 1, 12, i32::MAX - 1, i32::MAX];
+        for (i, user_id) in users.iter().enumerate() {
+            for secs in 0..3 {
+                catalog.put(&record(*user_id, id_at(secs * 10 + i as u64, 0))).await.unwrap();
+            }
+        }
+        for (i, user_id) in users.iter().enumerate() {
+            let expected: Vec<Uuid> = (0..3).rev().map(|secs| id_at(secs * 10 + i as u64, 0)).collect();
+            assert_eq!(ids(&catalog, *user_id, None, 10).await, expected, "user {}", user_id);
+            assert_eq!(ids(&catalog, *user_id, Some(expected[0]), 10).await, expected[1..], "user {}", user_id);
+            assert!(ids(&catalog, *user_id, Some(expected[2]), 10).await.is_empty(), "user {}", user_id);
+        }
+        assert!(ids(&catalog, 13, None, 10).await.is_empty());
+        assert!(catalog.get(13, id_at(5, 0)).await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn put_replaces_and_delete_removes() {
+        let (catalog, _dir) = catalog();
+        let id = id_at(1, 0);
+        catalog.put(&record(12, id)).await.unwrap();
+        catalog.set_status(12, id, ImageStatus::Trashed).await.unwrap();
+        assert_eq!(catalog.get(12, id).await.unwrap().unwrap().status, ImageStatus::Trashed);
+        assert_eq!(ids(&catalog, 12, None, 10).await, [id]);
+
+        catalog.delete(12, id).await.unwrap();
+        assert!(catalog.get(12, id).await.unwrap().is_none());
+        assert!(ids(&catalog, 12, None, 10).await.is_empty());
+        // deleting what is not there is not an error
+        catalog.delete(12, id).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn modify_reapplies_the_change_after_a_concurrent_write() {
+        let (catalog, _dir) = catalog();
+        let id = id_at(1, 0);
+        catalog.put(&record(12, id)).await.unwrap();
+
+        let mut calls = 0;
+        let written = catalog
+            .modify(12, id, &mut |record| {
+                calls += 1;
+                if calls == 1 {
+                    // another writer gets in between the read and the write
+                    let concurrent = ImageRecord { content_hash: "concurrent".to_owned(), ..record.clone() };
+                    catalog.tree.insert(key(12, &id), serde_json::to_vec(&concurrent).unwrap()).unwrap();
+                }
+                record.original_filename = "renamed.jpg".to_owned();
+                true
+            })
+            .await
+            .unwrap();
+        assert!(written);
+        assert_eq!(calls, 2);
+        let stored = catalog.get(12, id).await.unwrap().unwrap();
+        assert_eq!((stored.content_hash.as_str(), stored.original_filename.as_str()), ("concurrent", "renamed.jpg"));
+
+        // declined and missing records are left alone
+        assert!(!catalog.modify(12, id, &mut |_| false).await.unwrap());
+        assert!(!catalog.modify(12, id_at(2, 0), &mut |_| true).await.unwrap());
+        assert!(catalog.get(12, id_at(2, 0)).await.unwrap().is_none());
+    }
+}
+
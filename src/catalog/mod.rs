@@ -0,0 +1,168 @@
+mod embedded;
+mod scylla;
+
+pub use self::embedded::EmbeddedCatalog;
+pub use self::scylla::ScyllaCatalog;
+
+use crate::config::{CatalogBackend, Config};
+use crate::error::Error;
+
+use std::sync::Arc;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageStatus {
+    Active,
+    /// Replaced by `update_image`; the files live in the trash.
+    Trashed,
+}
+
+impl ImageStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImageStatus::Active => "active",
+            ImageStatus::Trashed => "trashed",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "active" => Some(ImageStatus::Active),
+            "trashed" => Some(ImageStatus::Trashed),
+            _ => None,
+        }
+    }
+}
+
+/// One stored variant file of an image.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VariantRecord {
+    /// Profile the variant was made with; empty for records written
+    /// before profiles were configurable.
+    #[serde(default)]
+    pub name: String,
+    pub suffix: String,
+    /// Extension of the variant file; empty means the record's `ext`.
+    #[serde(default)]
+    pub ext: String,
+    /// Pixel density the profile's bounds were multiplied by.
+    #[serde(default = "one")]
+    pub density: u32,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: u64,
+    /// Hex SHA-256 of the file, served as its `ETag`; empty for records
+    /// written before it was recorded.
+    #[serde(default)]
+    pub hash: String,
+}
+
+/// Area of the uploaded image the variants are made from.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+fn one() -> u32 {
+    1
+}
+
+/// Catalog row for an image. `image_id` is the time-based UUID that
+/// names its files, so records sort by upload time.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ImageRecord {
+    pub user_id: i32,
+    pub image_id: Uuid,
+    pub ext: String,
+    pub variants: Vec<VariantRecord>,
+    /// Hex SHA-256 of the uploaded source file.
+    pub content_hash: String,
+    pub original_filename: String,
+    pub status: ImageStatus,
+    /// Crop of the source kept at `{userId}/{imgName}.{ext}`, for
+    /// reprocessing; `None` when only the variants were stored.
+    #[serde(default)]
+    pub crop: Option<Crop>,
+}
+
+impl ImageRecord {
+    pub fn variant_ext<'a>(&'a self, variant: &'a VariantRecord) -> &'a str {
+        if variant.ext.is_empty() {
+            &self.ext
+        } else {
+            &variant.ext
+        }
+    }
+
+    /// `{imgName}_{suffix}.{ext}`, the file `variant` is stored as.
+    pub fn variant_file_name(&self, variant: &VariantRecord) -> String {
+        format!("{}_{}.{}", self.image_id, variant.suffix, self.variant_ext(variant))
+    }
+
+    /// `{imgName}.{ext}`, the uploaded source, if it was kept.
+    pub fn source_file_name(&self) -> Option<String> {
+        self.crop.map(|_| format!("{}.{}", self.image_id, self.ext))
+    }
+}
+
+/// Edit applied by `ImageCatalog::modify`; `false` leaves the record as it
+/// is.
+pub type RecordChange<'a> = dyn FnMut(&mut ImageRecord) -> bool + 'a;
+
+#[async_trait(?Send)]
+pub trait ImageCatalog: Send + Sync {
+    /// Inserts `record`, replacing any row with the same user and image id.
+    async fn put(&self, record: &ImageRecord) -> Result<(), Error>;
+    async fn get(&self, user_id: i32, image_id: Uuid) -> Result<Option<ImageRecord>, Error>;
+    /// Up to `limit` records, newest first, older than `before` if given.
+    async fn list(&self, user_id: i32, before: Option<Uuid>, limit: usize) -> Result<Vec<ImageRecord>, Error>;
+    async fn delete(&self, user_id: i32, image_id: Uuid) -> Result<(), Error>;
+
+    /// Reads the record, lets `change` edit it and writes it back unless
+    /// another writer changed the row in between, in which case `change`
+    /// runs again on the new row. Returns whether a record was written:
+    /// `false` when there is none or `change` declined.
+    async fn modify(&self, user_id: i32, image_id: Uuid, change: &mut RecordChange<'_>) -> Result<bool, Error>;
+
+    async fn set_status(&self, user_id: i32, image_id: Uuid, status: ImageStatus) -> Result<(), Error> {
+        self.modify(user_id, image_id, &mut |record| {
+            record.status = status;
+            true
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Drops the variant file stored under `suffix` and `ext`, and the
+    /// whole record once no variant is left.
+    async fn remove_variant(&self, user_id: i32, image_id: Uuid, suffix: &str, ext: &str) -> Result<(), Error> {
+        let mut emptied = false;
+        self.modify(user_id, image_id, &mut |record| {
+            let record_ext = record.ext.clone();
+            record.variants.retain(|v| {
+                let v_ext = if v.ext.is_empty() { &record_ext } else { &v.ext };
+                v.suffix != suffix || v_ext != ext
+            });
+            emptied = record.variants.is_empty();
+            !emptied
+        })
+        .await?;
+        if emptied {
+            self.delete(user_id, image_id).await?;
+        }
+        Ok(())
+    }
+}
+
+pub async fn from_config(config: &Config) -> anyhow::Result<Arc<dyn ImageCatalog>> {
+    match config.catalog.backend {
+        CatalogBackend::Embedded => Ok(Arc::new(EmbeddedCatalog::open(&config.catalog.embedded.path)?)),
+        CatalogBackend::Scylla => Ok(Arc::new(ScyllaCatalog::connect(&config.catalog.scylla).await?)),
+    }
+}
@@ -0,0 +1,988 @@
+use std::{env, fs, ops::Deref, str::FromStr};
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+
+use actix_web::http::header::HeaderValue;
+use anyhow::{anyhow, bail, Context, Result};
+use image::imageops::FilterType;
+use serde::{Deserialize, Deserializer};
+
+use crate::safe_path::is_variant_suffix;
+
+/// Config file used when `LILY_CONFIG` is not set.
+static DEFAULT_CONFIG_FILE: &str = "lily.toml";
+
+/// Minimum length `RedisSession` needs to derive its cookie key.
+const MIN_SESSION_KEY_LEN: usize = 32;
+
+/// HS256 secrets shorter than the hash output are trivially brute-forced.
+const MIN_JWT_SECRET_LEN: usize = 32;
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub paths: PathsConfig,
+    pub session: SessionConfig,
+    pub storage: StorageConfig,
+    pub catalog: CatalogConfig,
+    pub auth: AuthConfig,
+    pub variants: Variants,
+    pub avif: AvifConfig,
+    pub cache: CacheConfig,
+    pub limits: LimitsConfig,
+    pub workers: WorkersConfig,
+    pub resize: ResizeConfig,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            host: "127.0.0.1".to_owned(),
+            port: 7600,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct PathsConfig {
+    /// Root of the per-user image directories.
+    pub images: PathBuf,
+    /// Where replaced images are moved by `update_image`.
+    pub trash: PathBuf,
+    /// Scratch directory for the postman `/test_image` endpoint.
+    pub test: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// Files under `paths.images` and `paths.trash`.
+    #[default]
+    Local,
+    /// Process memory; contents are lost on restart.
+    Memory,
+    /// An S3-compatible bucket, see `[storage.s3]`.
+    S3,
+}
+
+impl FromStr for StorageBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(StorageBackend::Local),
+            "memory" => Ok(StorageBackend::Memory),
+            "s3" => Ok(StorageBackend::S3),
+            _ => Err(format!("unknown storage backend {:?}", s)),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    pub s3: S3Config,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct S3Config {
+    /// e.g. `https://s3.eu-west-1.amazonaws.com` or `http://127.0.0.1:9000`.
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    /// Prepended to every image key.
+    pub prefix: String,
+    /// Prepended to keys moved to the trash.
+    pub trash_prefix: String,
+    pub access_key: String,
+    pub secret_key: String,
+    /// Address the bucket as `{endpoint}/{bucket}` rather than as a
+    /// subdomain; MinIO and most self-hosted stores need this.
+    pub path_style: bool,
+}
+
+impl Default for S3Config {
+    fn default() -> Self {
+        S3Config {
+            endpoint: String::new(),
+            region: "us-east-1".to_owned(),
+            bucket: String::new(),
+            prefix: String::new(),
+            trash_prefix: "trash/".to_owned(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            path_style: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CatalogBackend {
+    /// A local sled database, see `[catalog.embedded]`.
+    #[default]
+    Embedded,
+    /// A Scylla or Cassandra cluster, see `[catalog.scylla]`.
+    Scylla,
+}
+
+impl FromStr for CatalogBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "embedded" => Ok(CatalogBackend::Embedded),
+            "scylla" => Ok(CatalogBackend::Scylla),
+            _ => Err(format!("unknown catalog backend {:?}", s)),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct CatalogConfig {
+    pub backend: CatalogBackend,
+    pub embedded: EmbeddedConfig,
+    pub scylla: ScyllaConfig,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct EmbeddedConfig {
+    /// Directory of the sled database; created when missing.
+    pub path: PathBuf,
+}
+
+impl Default for EmbeddedConfig {
+    fn default() -> Self {
+        EmbeddedConfig {
+            path: PathBuf::from("lily-catalog"),
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ScyllaConfig {
+    /// `host:port` contact points.
+    pub nodes: Vec<String>,
+    pub keyspace: String,
+    /// Used only when the keyspace has to be created.
+    pub replication_factor: u32,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Default for ScyllaConfig {
+    fn default() -> Self {
+        ScyllaConfig {
+            nodes: vec!["127.0.0.1:9042".to_owned()],
+            keyspace: "lily".to_owned(),
+            replication_factor: 1,
+            username: None,
+            password: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SessionConfig {
+    pub redis: String,
+    pub key: String,
+    pub cookie_name: String,
+    pub ttl: u32,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            redis: "127.0.0.1:6379".to_owned(),
+            key: String::new(),
+            cookie_name: "lily-session".to_owned(),
+            ttl: 86400,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum FitMode {
+    /// Fit within `width` x `height`.
+    #[default]
+    Inside,
+    /// Scale to `width`; `height` is ignored.
+    Width,
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Same format as the upload.
+    #[default]
+    Original,
+    Jpeg,
+    Png,
+    Webp,
+    /// Encoded in the background; see `AvifConfig`.
+    Avif,
+}
+
+impl OutputFormat {
+    /// File extension of a variant made from an upload with `source_ext`.
+    pub fn ext<'a>(&self, source_ext: &'a str) -> &'a str {
+        match self {
+            OutputFormat::Original => source_ext,
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+        }
+    }
+}
+
+fn default_formats() -> Vec<OutputFormat> {
+    vec![OutputFormat::Original]
+}
+
+/// `format = "webp"` or `format = ["original", "webp"]`.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<OutputFormat>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(OutputFormat),
+        Many(Vec<OutputFormat>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(format) => vec![format],
+        OneOrMany::Many(formats) => formats,
+    })
+}
+
+/// Resampling filter used to scale variants down.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3,
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(filter: ResizeFilter) -> Self {
+        match filter {
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Implementation that scales variants; both sample with the profile's
+/// `filter`.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeEngine {
+    /// `image::imageops::resize`.
+    #[default]
+    Imageops,
+    /// The fixed-point convolution in `resize.rs`, several times faster.
+    Fast,
+}
+
+impl FromStr for ResizeEngine {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "imageops" => Ok(ResizeEngine::Imageops),
+            "fast" => Ok(ResizeEngine::Fast),
+            _ => Err(format!("unknown resize engine {:?}", s)),
+        }
+    }
+}
+
+/// How variants are scaled, whatever their profile.
+#[derive(Deserialize, Clone, Copy, Debug, Default)]
+#[serde(default)]
+pub struct ResizeConfig {
+    pub engine: ResizeEngine,
+}
+
+/// Unsharp mask applied to variants that were scaled down.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct Sharpen {
+    /// Blur radius of the mask.
+    pub sigma: f32,
+    /// Minimum brightness difference that gets sharpened.
+    #[serde(default)]
+    pub threshold: i32,
+}
+
+/// Files written for every uploaded image, stored as
+/// `{imgName}_{suffix}.{ext}`, one per output format.
+#[derive(Deserialize, Clone, Debug)]
+pub struct VariantProfile {
+    pub name: String,
+    pub suffix: String,
+    pub width: u32,
+    #[serde(default)]
+    pub height: Option<u32>,
+    #[serde(default)]
+    pub fit: FitMode,
+    /// Formats written, the first being the one clients get by default.
+    #[serde(rename = "format", default = "default_formats", deserialize_with = "one_or_many")]
+    pub formats: Vec<OutputFormat>,
+    #[serde(default)]
+    pub filter: ResizeFilter,
+    #[serde(default)]
+    pub sharpen: Option<Sharpen>,
+    /// JPEG and lossy WebP quality, 1-100.
+    #[serde(default = "default_quality")]
+    pub quality: u8,
+    /// Write WebP losslessly; `quality` is then ignored for it.
+    #[serde(default)]
+    pub lossless: bool,
+    /// Extra pixel densities, e.g. `[2, 3]`, each written as
+    /// `{suffix}@{density}x` with the bounds multiplied.
+    #[serde(default)]
+    pub densities: Vec<u32>,
+}
+
+/// Largest pixel density a profile may ask for.
+const MAX_DENSITY: u32 = 4;
+
+fn default_quality() -> u8 {
+    85
+}
+
+/// The `[[variants]]` profiles, largest first.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(transparent)]
+pub struct Variants(Vec<VariantProfile>);
+
+impl Default for Variants {
+    /// The HD (1920x1080), SD (720x576) and mobile (320x240) rows of
+    /// docs/image.txt.
+    fn default() -> Self {
+        let profile = |name: &str, suffix: &str, width, height| VariantProfile {
+            name: name.to_owned(),
+            suffix: suffix.to_owned(),
+            width,
+            height: Some(height),
+            fit: FitMode::Inside,
+            formats: default_formats(),
+            filter: ResizeFilter::default(),
+            sharpen: None,
+            quality: default_quality(),
+            lossless: false,
+            densities: vec![],
+        };
+        Variants(vec![
+            profile("lg", "1920", 1920, 1080),
+            profile("md", "720", 720, 576),
+            profile("sm", "320", 320, 240),
+        ])
+    }
+}
+
+impl Deref for Variants {
+    type Target = [VariantProfile];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Encoder settings for every AVIF variant, and the pool they are
+/// encoded on in the background.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct AvifConfig {
+    /// 1 (slowest, smallest) to 10 (fastest).
+    pub speed: u8,
+    /// 1-100.
+    pub quality: u8,
+    /// Encodings running at once; 0 means one per CPU.
+    pub threads: usize,
+    /// Images waiting for a thread; the AVIF files of any more are dropped.
+    pub queue: usize,
+}
+
+impl AvifConfig {
+    /// The encoder pool's settings. Nothing answers its 503s, so there
+    /// is no `Retry-After`.
+    pub fn workers(&self) -> WorkersConfig {
+        WorkersConfig {
+            threads: self.threads,
+            queue: self.queue,
+            retry_after: 0,
+        }
+    }
+}
+
+impl Default for AvifConfig {
+    fn default() -> Self {
+        AvifConfig {
+            speed: 8,
+            quality: 70,
+            threads: 1,
+            queue: 16,
+        }
+    }
+}
+
+/// `Cache-Control` sent with served images, per route.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// `/getimage/...` and `/images/...`: a file name keeps naming the
+    /// same image, but reprocessing rewrites its bytes, so caches must
+    /// come back and revalidate against the `ETag` now and then.
+    pub files: String,
+    /// `/i/...`: the file behind it changes, e.g. once an AVIF encoding
+    /// is ready.
+    pub aliases: String,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        CacheConfig {
+            files: "public, max-age=86400".to_owned(),
+            aliases: "public, max-age=3600".to_owned(),
+        }
+    }
+}
+
+/// What an upload may contain. Byte limits answer 413, images whose
+/// header announces too many pixels 422, before anything is decoded.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct LimitsConfig {
+    /// Whole upload request, going by its `Content-Length`.
+    pub max_request_bytes: u64,
+    /// The image part of an upload.
+    pub max_image_bytes: u64,
+    pub max_width: u32,
+    pub max_height: u32,
+    /// Width times height.
+    pub max_pixels: u64,
+    /// The JSON metadata part of an upload.
+    pub max_metadata_bytes: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_request_bytes: 25 * 1024 * 1024,
+            max_image_bytes: 20 * 1024 * 1024,
+            max_width: 16384,
+            max_height: 16384,
+            max_pixels: 50_000_000,
+            max_metadata_bytes: 64 * 1024,
+        }
+    }
+}
+
+/// The pool that decodes, crops and encodes uploads.
+#[derive(Deserialize, Clone, Copy, Debug)]
+#[serde(default)]
+pub struct WorkersConfig {
+    /// Jobs running at once; 0 means one per CPU.
+    pub threads: usize,
+    /// Jobs waiting for a thread before uploads are refused with 503.
+    pub queue: usize,
+    /// Seconds sent in `Retry-After` with that 503.
+    pub retry_after: u64,
+}
+
+impl WorkersConfig {
+    pub fn threads(&self) -> usize {
+        match self.threads {
+            0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+            threads => threads,
+        }
+    }
+}
+
+impl Default for WorkersConfig {
+    fn default() -> Self {
+        WorkersConfig {
+            threads: 0,
+            queue: 32,
+            retry_after: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct AuthConfig {
+    pub jwt: JwtConfig,
+}
+
+/// Keys accepted for `Authorization: Bearer` tokens. A token is checked
+/// against the key of the algorithm named in its header; algorithms
+/// without a key here are rejected.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct JwtConfig {
+    pub hs256_secret: Option<String>,
+    /// PEM files holding the public keys.
+    pub rs256_public_key: Option<PathBuf>,
+    pub es256_public_key: Option<PathBuf>,
+    /// Required `iss` and `aud` claims, when set.
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    /// Clock skew allowed on `exp` and `nbf`, in seconds.
+    pub leeway: u64,
+}
+
+impl Default for JwtConfig {
+    fn default() -> Self {
+        JwtConfig {
+            hs256_secret: None,
+            rs256_public_key: None,
+            es256_public_key: None,
+            issuer: None,
+            audience: None,
+            leeway: 60,
+        }
+    }
+}
+
+impl Config {
+    /// Loads defaults, then the TOML file, then `LILY_*` environment overrides,
+    /// and validates the result.
+    pub fn load() -> Result<Config> {
+        let file = env::var("LILY_CONFIG").ok();
+        let mut config = match &file {
+            Some(file) => Config::from_file(Path::new(file))?,
+            None if Path::new(DEFAULT_CONFIG_FILE).is_file() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config> {
+        let raw = fs::read_to_string(path)
+            .with_context(|| format!("could not read config file {}", path.display()))?;
+        toml::from_str(&raw)
+            .with_context(|| format!("could not parse config file {}", path.display()))
+    }
+
+    fn apply_env(&mut self) -> Result<()> {
+        env_override("LILY_SERVER_HOST", &mut self.server.host)?;
+        env_override("LILY_SERVER_PORT", &mut self.server.port)?;
+        env_override("LILY_PATHS_IMAGES", &mut self.paths.images)?;
+        env_override("LILY_PATHS_TRASH", &mut self.paths.trash)?;
+        if let Ok(test) = env::var("LILY_PATHS_TEST") {
+            self.paths.test = Some(PathBuf::from(test));
+        }
+        env_override("LILY_SESSION_REDIS", &mut self.session.redis)?;
+        env_override("LILY_SESSION_KEY", &mut self.session.key)?;
+        env_override("LILY_SESSION_COOKIE_NAME", &mut self.session.cookie_name)?;
+        env_override("LILY_SESSION_TTL", &mut self.session.ttl)?;
+        env_override("LILY_STORAGE_BACKEND", &mut self.storage.backend)?;
+        env_override("LILY_STORAGE_S3_ENDPOINT", &mut self.storage.s3.endpoint)?;
+        env_override("LILY_STORAGE_S3_REGION", &mut self.storage.s3.region)?;
+        env_override("LILY_STORAGE_S3_BUCKET", &mut self.storage.s3.bucket)?;
+        env_override("LILY_STORAGE_S3_PREFIX", &mut self.storage.s3.prefix)?;
+        env_override("LILY_STORAGE_S3_TRASH_PREFIX", &mut self.storage.s3.trash_prefix)?;
+        env_override("LILY_STORAGE_S3_ACCESS_KEY", &mut self.storage.s3.access_key)?;
+        env_override("LILY_STORAGE_S3_SECRET_KEY", &mut self.storage.s3.secret_key)?;
+        env_override("LILY_STORAGE_S3_PATH_STYLE", &mut self.storage.s3.path_style)?;
+        env_override("LILY_CATALOG_BACKEND", &mut self.catalog.backend)?;
+        env_override("LILY_CATALOG_EMBEDDED_PATH", &mut self.catalog.embedded.path)?;
+        if let Ok(nodes) = env::var("LILY_CATALOG_SCYLLA_NODES") {
+            self.catalog.scylla.nodes = nodes.split(',').map(|n| n.trim().to_owned()).collect();
+        }
+        env_override("LILY_CATALOG_SCYLLA_KEYSPACE", &mut self.catalog.scylla.keyspace)?;
+        if let Ok(username) = env::var("LILY_CATALOG_SCYLLA_USERNAME") {
+            self.catalog.scylla.username = Some(username);
+        }
+        if let Ok(password) = env::var("LILY_CATALOG_SCYLLA_PASSWORD") {
+            self.catalog.scylla.password = Some(password);
+        }
+        let jwt = &mut self.auth.jwt;
+        if let Ok(secret) = env::var("LILY_AUTH_JWT_HS256_SECRET") {
+            jwt.hs256_secret = Some(secret);
+        }
+        if let Ok(key) = env::var("LILY_AUTH_JWT_RS256_PUBLIC_KEY") {
+            jwt.rs256_public_key = Some(PathBuf::from(key));
+        }
+        if let Ok(key) = env::var("LILY_AUTH_JWT_ES256_PUBLIC_KEY") {
+            jwt.es256_public_key = Some(PathBuf::from(key));
+        }
+        if let Ok(issuer) = env::var("LILY_AUTH_JWT_ISSUER") {
+            jwt.issuer = Some(issuer);
+        }
+        if let Ok(audience) = env::var("LILY_AUTH_JWT_AUDIENCE") {
+            jwt.audience = Some(audience);
+        }
+        env_override("LILY_AUTH_JWT_LEEWAY", &mut jwt.leeway)?;
+        env_override("LILY_AVIF_SPEED", &mut self.avif.speed)?;
+        env_override("LILY_AVIF_QUALITY", &mut self.avif.quality)?;
+        env_override("LILY_AVIF_THREADS", &mut self.avif.threads)?;
+        env_override("LILY_AVIF_QUEUE", &mut self.avif.queue)?;
+        env_override("LILY_CACHE_FILES", &mut self.cache.files)?;
+        env_override("LILY_CACHE_ALIASES", &mut self.cache.aliases)?;
+        env_override("LILY_LIMITS_MAX_REQUEST_BYTES", &mut self.limits.max_request_bytes)?;
+        env_override("LILY_LIMITS_MAX_IMAGE_BYTES", &mut self.limits.max_image_bytes)?;
+        env_override("LILY_LIMITS_MAX_WIDTH", &mut self.limits.max_width)?;
+        env_override("LILY_LIMITS_MAX_HEIGHT", &mut self.limits.max_height)?;
+        env_override("LILY_LIMITS_MAX_PIXELS", &mut self.limits.max_pixels)?;
+        env_override("LILY_LIMITS_MAX_METADATA_BYTES", &mut self.limits.max_metadata_bytes)?;
+        env_override("LILY_WORKERS_THREADS", &mut self.workers.threads)?;
+        env_override("LILY_WORKERS_QUEUE", &mut self.workers.queue)?;
+        env_override("LILY_WORKERS_RETRY_AFTER", &mut self.workers.retry_after)?;
+        env_override("LILY_RESIZE_ENGINE", &mut self.resize.engine)?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self.storage.backend {
+            StorageBackend::Local => {
+                writable_dir("paths.images", &self.paths.images)?;
+                writable_dir("paths.trash", &self.paths.trash)?;
+            }
+            StorageBackend::S3 => {
+                let s3 = &self.storage.s3;
+                for (name, value) in [
+                    ("storage.s3.endpoint", &s3.endpoint),
+                    ("storage.s3.bucket", &s3.bucket),
+                    ("storage.s3.access_key", &s3.access_key),
+                    ("storage.s3.secret_key", &s3.secret_key),
+                ] {
+                    if value.is_empty() {
+                        bail!("{} is not configured", name);
+                    }
+                }
+                if !s3.endpoint.starts_with("http://") && !s3.endpoint.starts_with("https://") {
+                    bail!("storage.s3.endpoint must start with http:// or https://");
+                }
+                if s3.prefix == s3.trash_prefix {
+                    bail!("storage.s3.prefix and storage.s3.trash_prefix must differ");
+                }
+            }
+            StorageBackend::Memory => {}
+        }
+        match self.catalog.backend {
+            CatalogBackend::Embedded => {
+                if self.catalog.embedded.path.as_os_str().is_empty() {
+                    bail!("catalog.embedded.path is not configured");
+                }
+            }
+            CatalogBackend::Scylla => {
+                let scylla = &self.catalog.scylla;
+                if scylla.nodes.is_empty() {
+                    bail!("catalog.scylla.nodes is empty");
+                }
+                let valid_keyspace = !scylla.keyspace.is_empty()
+                    && scylla.keyspace.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if !valid_keyspace {
+                    bail!("catalog.scylla.keyspace must be a plain identifier");
+                }
+            }
+        }
+        if let Some(test) = &self.paths.test {
+            writable_dir("paths.test", test)?;
+        }
+        self.validate_variants()?;
+        if !(1..=10).contains(&self.avif.speed) || !(1..=100).contains(&self.avif.quality) {
+            bail!("avif.speed must be between 1 and 10 and avif.quality between 1 and 100");
+        }
+        for (name, value) in [("cache.files", &self.cache.files), ("cache.aliases", &self.cache.aliases)] {
+            if value.is_empty() || HeaderValue::from_str(value).is_err() {
+                bail!("{} must be a non-empty Cache-Control value", name);
+            }
+        }
+        let limits = &self.limits;
+        if limits.max_image_bytes == 0
+            || limits.max_width == 0
+            || limits.max_height == 0
+            || limits.max_pixels == 0
+            || limits.max_metadata_bytes == 0
+        {
+            bail!("limits.max_image_bytes, max_width, max_height, max_pixels and max_metadata_bytes must be above 0");
+        }
+        if limits.max_request_bytes < limits.max_image_bytes {
+            bail!("limits.max_request_bytes must be at least limits.max_image_bytes");
+        }
+        if self.session.key.len() < MIN_SESSION_KEY_LEN {
+            bail!("session.key must be at least {} bytes long", MIN_SESSION_KEY_LEN);
+        }
+        if self.auth.jwt.hs256_secret.as_ref().is_some_and(|s| s.len() < MIN_JWT_SECRET_LEN) {
+            bail!("auth.jwt.hs256_secret must be at least {} bytes long", MIN_JWT_SECRET_LEN);
+        }
+        let addr = self.bind_address();
+        let mut resolved = addr
+            .to_socket_addrs()
+            .with_context(|| format!("invalid bind address {}", addr))?;
+        if resolved.next().is_none() {
+            bail!("bind address {} did not resolve", addr);
+        }
+        Ok(())
+    }
+
+    fn validate_variants(&self) -> Result<()> {
+        if self.variants.is_empty() {
+            bail!("at least one [[variants]] profile is required");
+        }
+        for (i, variant) in self.variants.iter().enumerate() {
+            if !is_variant_suffix(&variant.suffix) {
+                bail!("variants.{}.suffix must be alphanumeric", variant.name);
+            }
+            if variant.width == 0 || variant.height == Some(0) {
+                bail!("variants.{} needs a non-zero width and height", variant.name);
+            }
+            if !(1..=100).contains(&variant.quality) {
+                bail!("variants.{}.quality must be between 1 and 100", variant.name);
+            }
+            let formats = &variant.formats;
+            let repeated = formats.iter().enumerate().any(|(i, format)| formats[..i].contains(format));
+            if formats.is_empty() || repeated {
+                bail!("variants.{}.format must list each format at most once", variant.name);
+            }
+            if let Some(sharpen) = variant.sharpen {
+                if !sharpen.sigma.is_finite() || sharpen.sigma <= 0.0 || sharpen.threshold < 0 {
+                    bail!("variants.{}.sharpen needs sigma > 0 and threshold >= 0", variant.name);
+                }
+            }
+            let ascending = variant.densities.windows(2).all(|pair| pair[0] < pair[1]);
+            if !ascending || variant.densities.iter().any(|d| !(2..=MAX_DENSITY).contains(d)) {
+                bail!(
+                    "variants.{}.densities must be ascending values between 2 and {}",
+                    variant.name, MAX_DENSITY
+                );
+            }
+            let duplicate = self.variants[..i]
+                .iter()
+                .any(|other| other.name == variant.name || other.suffix == variant.suffix);
+            if duplicate {
+                bail!("variants.{} repeats the name or suffix of another profile", variant.name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn bind_address(&self) -> String {
+        format!("{}:{}", self.server.host, self.server.port)
+    }
+}
+
+fn env_override<T>(key: &str, target: &mut T) -> Result<()>
+where
+    T: FromStr,
+    T::Err: std::fmt::Display,
+{
+    if let Ok(value) = env::var(key) {
+        *target = value
+            .parse()
+            .map_err(|e| anyhow!("invalid value for {}: {}", key, e))?;
+    }
+    Ok(())
+}
+
+fn writable_dir(name: &str, dir: &Path) -> Result<()> {
+    if dir.as_os_str().is_empty() {
+        bail!("{} is not configured", name);
+    }
+    if !dir.is_dir() {
+        bail!("{} ({}) is not a directory", name, dir.display());
+    }
+    let probe = dir.join(".lily-write-probe");
+    fs::write(&probe, b"")
+        .with_context(|| format!("{} ({}) is not writable", name, dir.display()))?;
+    fs::remove_file(&probe)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Enough on top of the defaults to pass `validate` without touching
+    /// the filesystem.
+    const BASE: &str = r#"
+        [session]
+        key = "0123456789abcdef0123456789abcdef"
+        [storage]
+        backend = "memory"
+    "#;
+
+    fn parse(extra: &str) -> Config {
+        toml::from_str(&format!("{}\n{}", extra, BASE)).unwrap()
+    }
+
+    fn invalid(config: &Config) -> String {
+        config.validate().unwrap_err().to_string()
+    }
+
+    fn profile(name: &str, suffix: &str, rest: &str) -> String {
+        format!("[[variants]]\nname = {:?}\nsuffix = {:?}\nwidth = 720\nheight = 576\n{}\n", name, suffix, rest)
+    }
+
+    #[test]
+    fn defaults_and_the_example_are_valid() {
+        parse("").validate().unwrap();
+        let mut example: Config = toml::from_str(include_str!("../lily.example.toml")).unwrap();
+        example.storage.backend = StorageBackend::Memory;
+        example.validate().unwrap();
+        assert_eq!(example.variants.len(), 3);
+    }
+
+    #[test]
+    fn refuses_bad_variant_suffixes() {
+        for suffix in ["", "7/20", "720@2x", "a b", "..", "72é"] {
+            let config = parse(&profile("md", suffix, ""));
+            assert_eq!(invalid(&config), "variants.md.suffix must be alphanumeric", "{:?}", suffix);
+        }
+        let config = parse(&(profile("md", "720", "") + &profile("sm", "720", "")));
+        assert_eq!(invalid(&config), "variants.sm repeats the name or suffix of another profile");
+        let config = parse(&(profile("md", "720", "") + &profile("md", "320", "")));
+        assert_eq!(invalid(&config), "variants.md repeats the name or suffix of another profile");
+    }
+
+    #[test]
+    fn refuses_bad_variant_settings() {
+        let config = parse("[[variants]]\nname = \"md\"\nsuffix = \"720\"\nwidth = 0\n");
+        assert_eq!(invalid(&config), "variants.md needs a non-zero width and height");
+        let config = parse(&profile("md", "720", "quality = 0"));
+        assert_eq!(invalid(&config), "variants.md.quality must be between 1 and 100");
+        let config = parse(&profile("md", "720", "format = [\"webp\", \"webp\"]"));
+        assert_eq!(invalid(&config), "variants.md.format must list each format at most once");
+        let config = parse(&profile("md", "720", "sharpen = { sigma = 0.0, threshold = 1 }"));
+        assert_eq!(invalid(&config), "variants.md.sharpen needs sigma > 0 and threshold >= 0");
+        for densities in ["[1]", "[3, 2]", "[2, 2]", "[5]"] {
+            let config = parse(&profile("md", "720", &format!("densities = {}", densities)));
+            assert!(invalid(&config).starts_with("variants.md.densities"), "{}", densities);
+        }
+        let mut config = parse("");
+        config.variants = Variants(vec![]);
+        assert_eq!(invalid(&config), "at least one [[variants]] profile is required");
+    }
+
+    #[test]
+    fn refuses_zero_and_inconsistent_limits() {
+        for limit in ["max_image_bytes", "max_width", "max_height", "max_pixels", "max_metadata_bytes"] {
+            let config = parse(&format!("[limits]\n{} = 0\n", limit));
+            assert!(invalid(&config).contains("must be above 0"), "{}", limit);
+        }
+        let config = parse("[limits]\nmax_request_bytes = 1000\nmax_image_bytes = 1001\n");
+        assert_eq!(invalid(&config), "limits.max_request_bytes must be at least limits.max_image_bytes");
+    }
+
+    #[test]
+    fn refuses_missing_keys() {
+        let mut config = parse("");
+        config.session.key = "too short".to_owned();
+        assert_eq!(invalid(&config), "session.key must be at least 32 bytes long");
+
+        let mut config = parse("");
+        config.auth.jwt.hs256_secret = Some("too short".to_owned());
+        assert_eq!(invalid(&config), "auth.jwt.hs256_secret must be at least 32 bytes long");
+
+        let mut config = parse("");
+        config.storage.backend = StorageBackend::S3;
+        config.storage.s3 = S3Config {
+            endpoint: "http://127.0.0.1:9000".to_owned(),
+            bucket: "lily-images".to_owned(),
+            access_key: "lily".to_owned(),
+            secret_key: "lily-secret".to_owned(),
+            ..S3Config::default()
+        };
+        config.validate().unwrap();
+        for (name, mut s3) in [
+            ("endpoint", S3Config { endpoint: String::new(), ..config.storage.s3.clone() }),
+            ("bucket", S3Config { bucket: String::new(), ..config.storage.s3.clone() }),
+            ("access_key", S3Config { access_key: String::new(), ..config.storage.s3.clone() }),
+            ("secret_key", S3Config { secret_key: String::new(), ..config.storage.s3.clone() }),
+        ] {
+            std::mem::swap(&mut config.storage.s3, &mut s3);
+            assert_eq!(invalid(&config), format!("storage.s3.{} is not configured", name));
+            std::mem::swap(&mut config.storage.s3, &mut s3);
+        }
+        config.storage.s3.prefix = config.storage.s3.trash_prefix.clone();
+        assert_eq!(invalid(&config), "storage.s3.prefix and storage.s3.trash_prefix must differ");
+
+        let mut config = parse("");
+        config.catalog.backend = CatalogBackend::Scylla;
+        config.catalog.scylla.keyspace = "lily; DROP".to_owned();
+        assert_eq!(invalid(&config), "catalog.scylla.keyspace must be a plain identifier");
+        config.catalog.scylla.nodes.clear();
+        assert_eq!(invalid(&config), "catalog.scylla.nodes is empty");
+    }
+
+    #[test]
+    fn refuses_unusable_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = parse("");
+        config.storage.backend = StorageBackend::Local;
+        config.paths.images = dir.path().to_owned();
+        config.paths.trash = dir.path().join("missing");
+        assert!(invalid(&config).starts_with("paths.trash"), "{}", invalid(&config));
+        config.paths.trash = dir.path().to_owned();
+        config.validate().unwrap();
+    }
+
+    /// The only test that sets `LILY_*` variables, so that no other test
+    /// sees them.
+    #[test]
+    fn environment_overrides_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("lily.toml");
+        let toml = format!("[server]\nport = 7600\n[limits]\nmax_width = 100\nmax_height = 100\n{}", BASE);
+        fs::write(&file, toml).unwrap();
+        let vars = [
+            ("LILY_CONFIG", file.to_str().unwrap()),
+            ("LILY_SERVER_PORT", "7611"),
+            ("LILY_LIMITS_MAX_WIDTH", "200"),
+            ("LILY_CATALOG_SCYLLA_NODES", "10.0.0.1:9042, 10.0.0.2:9042"),
+            ("LILY_AUTH_JWT_ISSUER", "lily-web"),
+            ("LILY_RESIZE_ENGINE", "fast"),
+        ];
+        for (key, value) in vars {
+            env::set_var(key, value);
+        }
+        let loaded = Config::load();
+        env::set_var("LILY_SERVER_PORT", "not-a-port");
+        let bad_port = Config::load();
+        env::set_var("LILY_SERVER_PORT", "7611");
+        env::set_var("LILY_LIMITS_MAX_WIDTH", "0");
+        let zero_width = Config::load();
+        for (key, _) in vars {
+            env::remove_var(key);
+        }
+
+        let config = loaded.unwrap();
+        assert_eq!(config.server.port, 7611);
+        assert_eq!(config.limits.max_width, 200);
+        // what the environment leaves alone comes from the file
+        assert_eq!(config.limits.max_height, 100);
+        assert_eq!(config.catalog.scylla.nodes, ["10.0.0.1:9042", "10.0.0.2:9042"]);
+        assert_eq!(config.auth.jwt.issuer.as_deref(), Some("lily-web"));
+        assert_eq!(config.resize.engine, ResizeEngine::Fast);
+        assert!(bad_port.unwrap_err().to_string().starts_with("invalid value for LILY_SERVER_PORT"));
+        // overrides are validated like the file
+        assert!(zero_width.unwrap_err().to_string().contains("must be above 0"));
+    }
+}
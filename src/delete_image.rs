@@ -1,11 +1,44 @@
-use crate::error::Error;
-use crate::PATH;
-use std::fs;
+use crate::error::{Error, ErrorKind};
+use crate::storage::Storage;
+use crate::catalog::ImageCatalog;
+use crate::auth::AuthSession;
+use crate::safe_path::{image_id, ImageName, UserId};
 
-use actix_web::{HttpResponse, web};
+use actix_web::{HttpRequest, HttpResponse, web};
 
-pub async fn delete_image(payload: web::Path<(String, String)>) -> Result<HttpResponse, Error> {
-    let full_path = format!("{}/{}/{}", PATH, &payload.0, &payload.1);
-    fs::remove_file(&full_path)?;
+/// `image_name` is either one variant file, `{imgName}_{suffix}.{ext}`,
+/// or a bare `{imgName}` to delete every variant in its catalog record.
+/// The kept source goes with the last variant.
+pub async fn delete_image(req: HttpRequest, storage: web::Data<dyn Storage>, catalog: web::Data<dyn ImageCatalog>, payload: web::Path<(String, String)>) -> Result<HttpResponse, Error> {
+    let user_id = UserId::parse(&payload.0)?;
+    req.user_info()?.owner(Some(user_id.get()), "delete_image")?;
+    if !payload.1.contains('.') {
+        let id = image_id(&payload.1)?;
+        let record = catalog
+            .get(user_id.get(), id)
+            .await?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "image not found."))?;
+        let files = record.variants.iter().map(|v| record.variant_file_name(v)).chain(record.source_file_name());
+        for file_name in files {
+            let key = format!("{}/{}", user_id, file_name);
+            if storage.exists(&key).await? {
+                storage.delete(&key).await?;
+            }
+        }
+        catalog.delete(user_id.get(), id).await?;
+        return Ok(HttpResponse::Ok().body("Deleted."));
+    }
+    let name = ImageName::parse(&payload.1)?;
+    storage.delete(&name.key(user_id)).await?;
+    if let Some(record) = catalog.get(user_id.get(), name.id).await? {
+        let last = record.variants.iter().all(|v| v.suffix == name.suffix && record.variant_ext(v) == name.ext);
+        if let (true, Some(source)) = (last, record.source_file_name()) {
+            let key = format!("{}/{}", user_id, source);
+            if storage.exists(&key).await? {
+                storage.delete(&key).await?;
+            }
+        }
+    }
+    catalog.remove_variant(user_id.get(), name.id, &name.suffix, name.ext).await?;
     Ok(HttpResponse::Ok().body("Deleted."))
 }
@@ -1,149 +1,385 @@
 use actix_multipart::MultipartError;
-use actix_web::{http::StatusCode, HttpResponse, error::BlockingError};
+use actix_web::{http::{header, StatusCode}, HttpResponse, error::{BlockingError, PayloadError}};
 use derive_more::Display;
 use image::ImageError;
 use serde::Serialize;
 
-impl From<uuid::Error> for Error {
-    fn from(e: uuid::Error) -> Self {
+/// What went wrong, as far as the client is concerned. Each kind has a
+/// fixed status and a machine-readable code that clients may match on;
+/// the human-readable detail is free to change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// Malformed multipart body, metadata or query.
+    InvalidRequest,
+    /// A user id, image name or storage key that fails `safe_path`.
+    InvalidPath,
+    Unauthenticated,
+    Forbidden,
+    NotFound,
+    Conflict,
+    PayloadTooLarge,
+    UnsupportedMediaType,
+    /// Well-formed request whose image cannot be processed.
+    UnprocessableImage,
+    Internal,
+    Unavailable,
+}
+
+impl ErrorKind {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorKind::InvalidRequest | ErrorKind::InvalidPath => StatusCode::BAD_REQUEST,
+            ErrorKind::Unauthenticated => StatusCode::UNAUTHORIZED,
+            ErrorKind::Forbidden => StatusCode::FORBIDDEN,
+            ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            ErrorKind::Conflict => StatusCode::CONFLICT,
+            ErrorKind::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorKind::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ErrorKind::UnprocessableImage => StatusCode::UNPROCESSABLE_ENTITY,
+            ErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorKind::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorKind::InvalidRequest => "INVALID_REQUEST",
+            ErrorKind::InvalidPath => "INVALID_PATH",
+            ErrorKind::Unauthenticated => "UN_AUTHENTICATED_USER",
+            ErrorKind::Forbidden => "FORBIDDEN",
+            ErrorKind::NotFound => "NOT_FOUND",
+            ErrorKind::Conflict => "CONFLICT",
+            ErrorKind::PayloadTooLarge => "PAYLOAD_TOO_LARGE",
+            ErrorKind::UnsupportedMediaType => "UNSUPPORTED_MEDIA_TYPE",
+            ErrorKind::UnprocessableImage => "UNPROCESSABLE_IMAGE",
+            ErrorKind::Internal => "INTERNAL",
+            ErrorKind::Unavailable => "UNAVAILABLE",
+        }
+    }
+
+    fn from_status(status: StatusCode) -> Self {
+        match status {
+            StatusCode::UNAUTHORIZED => ErrorKind::Unauthenticated,
+            StatusCode::FORBIDDEN => ErrorKind::Forbidden,
+            StatusCode::NOT_FOUND => ErrorKind::NotFound,
+            StatusCode::CONFLICT => ErrorKind::Conflict,
+            StatusCode::PAYLOAD_TOO_LARGE => ErrorKind::PayloadTooLarge,
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => ErrorKind::UnsupportedMediaType,
+            StatusCode::UNPROCESSABLE_ENTITY => ErrorKind::UnprocessableImage,
+            StatusCode::SERVICE_UNAVAILABLE => ErrorKind::Unavailable,
+            s if s.is_client_error() => ErrorKind::InvalidRequest,
+            _ => ErrorKind::Internal,
+        }
+    }
+}
+
+/// A problem with one named input, e.g. a metadata field or path segment.
+#[derive(Serialize, Clone, Debug)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Display, Debug)]
+#[display(fmt = "{}: {}", "kind.code()", message)]
+pub struct Error {
+    kind: ErrorKind,
+    message: String,
+    details: Vec<FieldError>,
+    /// Seconds for `Retry-After`, when trying again later may help.
+    retry_after: Option<u64>,
+}
+
+impl std::error::Error for Error {}
+
+impl Error {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
         Error {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e.to_string(),
+            kind,
+            message: message.into(),
+            details: vec![],
+            retry_after: None,
+        }
+    }
+
+    /// Attaches a field-level detail.
+    pub fn with_field(mut self, field: &str, message: impl Into<String>) -> Self {
+        self.details.push(FieldError {
+            field: field.to_owned(),
+            message: message.into(),
+        });
+        self
+    }
+
+    pub fn with_retry_after(mut self, seconds: u64) -> Self {
+        self.retry_after = Some(seconds);
+        self
+    }
+
+    pub fn get_status(&self) -> StatusCode {
+        self.kind.status()
+    }
+
+    pub fn get_message(&self) -> String {
+        self.message.clone()
+    }
+}
+
+/// `detail` of every `Internal` error; the real message is only logged.
+const INTERNAL_DETAIL: &str = "The server could not complete the request.";
+/// `detail` of every `Unavailable` error.
+const UNAVAILABLE_DETAIL: &str = "The service is busy or a backend is unreachable; try again later.";
+
+/// RFC 7807 problem details, with the error code and field details as
+/// extension members.
+#[derive(Serialize)]
+pub struct ErrorResponse {
+    #[serde(rename = "type")]
+    type_: String,
+    title: &'static str,
+    status: u16,
+    detail: String,
+    code: &'static str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    errors: Vec<FieldError>,
+}
+
+impl actix_web::ResponseError for Error {
+    fn status_code(&self) -> StatusCode {
+        self.get_status()
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse {
+        let status = self.status_code();
+        // server-side messages name paths, backends and queries: they are
+        // logged, and the client gets a fixed detail instead
+        let (detail, errors) = match self.kind {
+            ErrorKind::Internal => (INTERNAL_DETAIL.to_owned(), vec![]),
+            ErrorKind::Unavailable => (UNAVAILABLE_DETAIL.to_owned(), vec![]),
+            _ => (self.get_message(), self.details.clone()),
+        };
+        if status.is_server_error() {
+            // refusing work under load is expected, not worth an error line
+            if self.retry_after.is_none() {
+                log::error!("{}", self);
+            } else {
+                log::debug!("{}", self);
+            }
+        }
+        let mut res = HttpResponse::build(status);
+        if let Some(seconds) = self.retry_after {
+            res.insert_header((header::RETRY_AFTER, seconds));
         }
+        res.insert_header((header::CONTENT_TYPE, "application/problem+json"))
+            .json(ErrorResponse {
+                type_: format!("urn:lily-image:error:{}", self.kind.code()),
+                title: status.canonical_reason().unwrap_or_default(),
+                status: status.as_u16(),
+                detail,
+                code: self.kind.code(),
+                errors,
+            })
+    }
+}
+
+impl From<uuid::Error> for Error {
+    fn from(e: uuid::Error) -> Self {
+        Error::new(ErrorKind::InvalidRequest, e.to_string())
     }
 }
 
 impl From<actix_web::Error> for Error {
     fn from(e: actix_web::Error) -> Self {
-        Error {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e.to_string(),
-        }
+        let kind = ErrorKind::from_status(e.as_response_error().status_code());
+        Error::new(kind, e.to_string())
     }
 }
 
 impl From<anyhow::Error> for Error {
     fn from(e: anyhow::Error) -> Self {
-        Error {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e.to_string(),
-        }
+        Error::new(ErrorKind::Internal, e.to_string())
     }
 }
 
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
-        Error {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e.to_string(),
-        }
+        let kind = match e.kind() {
+            std::io::ErrorKind::NotFound => ErrorKind::NotFound,
+            std::io::ErrorKind::AlreadyExists => ErrorKind::Conflict,
+            _ => ErrorKind::Internal,
+        };
+        Error::new(kind, e.to_string())
     }
 }
 
 impl From<jsonwebtoken::errors::Error> for Error {
     fn from(e: jsonwebtoken::errors::Error) -> Self {
-        Error {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e.to_string(),
-        }
+        Error::new(ErrorKind::Unauthenticated, e.to_string())
     }
 }
 
 impl From<std::num::ParseIntError> for Error {
     fn from(e: std::num::ParseIntError) -> Self {
-        Error {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e.to_string(),
-        }
+        Error::new(ErrorKind::InvalidRequest, e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        let kind = if e.is_connect() || e.is_timeout() {
+            ErrorKind::Unavailable
+        } else {
+            ErrorKind::Internal
+        };
+        Error::new(kind, e.to_string())
+    }
+}
+
+impl From<scylla::transport::errors::QueryError> for Error {
+    fn from(e: scylla::transport::errors::QueryError) -> Self {
+        use scylla::transport::errors::QueryError;
+        let kind = match e {
+            QueryError::TimeoutError | QueryError::IoError(_) => ErrorKind::Unavailable,
+            _ => ErrorKind::Internal,
+        };
+        Error::new(kind, e.to_string())
     }
 }
+
+impl From<sled::Error> for Error {
+    fn from(e: sled::Error) -> Self {
+        Error::new(ErrorKind::Internal, e.to_string())
+    }
+}
+
 impl From<ImageError> for Error {
     fn from(e: ImageError) -> Self {
-        Error {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e.to_string(),
-        }
+        let kind = match e {
+            ImageError::Decoding(_) | ImageError::Unsupported(_) | ImageError::Parameter(_) => ErrorKind::UnprocessableImage,
+            ImageError::Limits(_) => ErrorKind::PayloadTooLarge,
+            _ => ErrorKind::Internal,
+        };
+        Error::new(kind, e.to_string())
     }
 }
 
 impl From<BlockingError> for Error {
     fn from(e: BlockingError) -> Self {
-        Error {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e.to_string(),
-        }
+        Error::new(ErrorKind::Internal, e.to_string())
     }
 }
 
 impl From<MultipartError> for Error {
     fn from(e: MultipartError) -> Self {
-        Error {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e.to_string(),
-        }
+        let kind = match e {
+            MultipartError::Payload(PayloadError::Overflow) => ErrorKind::PayloadTooLarge,
+            _ => ErrorKind::InvalidRequest,
+        };
+        Error::new(kind, e.to_string())
     }
 }
 
+/// For our own (de)serialization, e.g. catalog rows. Client JSON is
+/// mapped to `InvalidRequest` where it is parsed.
 impl From<serde_json::Error> for Error {
     fn from(e: serde_json::Error) -> Self {
-        Error {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e.to_string(),
-        }
+        Error::new(ErrorKind::Internal, e.to_string())
     }
 }
 
-//
+/// Plain messages are server-side failures; client errors name a kind.
 impl From<String> for Error {
     fn from(e: String) -> Self {
-        Error {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e,
-        }
+        Error::new(ErrorKind::Internal, e)
     }
 }
 
 impl From<&str> for Error {
     fn from(e: &str) -> Self {
-        Error {
-            status: StatusCode::INTERNAL_SERVER_ERROR,
-            message: e.to_string(),
-        }
+        Error::new(ErrorKind::Internal, e)
     }
 }
 
-#[derive(Display, Debug)]
-#[display(fmt = "status: {}", status)]
-pub struct Error {
-    status: StatusCode,
-    message: String,
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::ResponseError;
 
-impl Error {
-    pub fn get_status(&self) -> StatusCode {
-        self.status
+    const KINDS: [(ErrorKind, u16, &str); 11] = [
+        (ErrorKind::InvalidRequest, 400, "INVALID_REQUEST"),
+        (ErrorKind::InvalidPath, 400, "INVALID_PATH"),
+        (ErrorKind::Unauthenticated, 401, "UN_AUTHENTICATED_USER"),
+        (ErrorKind::Forbidden, 403, "FORBIDDEN"),
+        (ErrorKind::NotFound, 404, "NOT_FOUND"),
+        (ErrorKind::Conflict, 409, "CONFLICT"),
+        (ErrorKind::PayloadTooLarge, 413, "PAYLOAD_TOO_LARGE"),
+        (ErrorKind::UnsupportedMediaType, 415, "UNSUPPORTED_MEDIA_TYPE"),
+        (ErrorKind::UnprocessableImage, 422, "UNPROCESSABLE_IMAGE"),
+        (ErrorKind::Internal, 500, "INTERNAL"),
+        (ErrorKind::Unavailable, 503, "UNAVAILABLE"),
+    ];
+
+    async fn body(e: &Error) -> serde_json::Value {
+        let bytes = actix_web::body::to_bytes(e.error_response().into_body()).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
     }
 
-    pub fn get_message(&self) -> String {
-        self.message.clone()
+    #[test]
+    fn kinds_map_to_their_status_and_code() {
+        for (kind, status, code) in KINDS {
+            assert_eq!(kind.status().as_u16(), status, "{:?}", kind);
+            assert_eq!(kind.code(), code);
+        }
     }
-}
 
-#[derive(Serialize)]
-pub struct ErrorResponse {
-    status: u16,
-    message: String,
-}
+    #[test]
+    fn statuses_map_back_to_their_kind() {
+        for (kind, status, _) in KINDS {
+            // both 400s come back as the general one
+            if kind != ErrorKind::InvalidPath && kind != ErrorKind::InvalidRequest {
+                assert_eq!(ErrorKind::from_status(StatusCode::from_u16(status).unwrap()), kind);
+            }
+        }
+        assert_eq!(ErrorKind::from_status(StatusCode::METHOD_NOT_ALLOWED), ErrorKind::InvalidRequest);
+        assert_eq!(ErrorKind::from_status(StatusCode::BAD_GATEWAY), ErrorKind::Internal);
+    }
 
-impl actix_web::ResponseError for Error {
-    fn status_code(&self) -> StatusCode {
-        self.get_status()
+    #[actix_web::test]
+    async fn client_errors_are_problem_details() {
+        let e = Error::new(ErrorKind::InvalidPath, "invalid imgName").with_field("imgName", "must be {timeuuid}_{suffix}.{ext}");
+        let res = e.error_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "application/problem+json");
+        assert_eq!(
+            body(&e).await,
+            serde_json::json!({
+                "type": "urn:lily-image:error:INVALID_PATH",
+                "title": "Bad Request",
+                "status": 400,
+                "detail": "invalid imgName",
+                "code": "INVALID_PATH",
+                "errors": [{ "field": "imgName", "message": "must be {timeuuid}_{suffix}.{ext}" }],
+            })
+        );
+        // without field details there is no `errors` member
+        let e = Error::new(ErrorKind::NotFound, "image not found.");
+        assert!(body(&e).await.get("errors").is_none());
     }
 
-    fn error_response(&self) -> actix_web::HttpResponse {
-        HttpResponse::build(self.status_code()).json(ErrorResponse {
-            status: self.status_code().as_u16(),
-            message: self.get_message()
-        })
+    #[actix_web::test]
+    async fn server_errors_hide_their_message() {
+        let e = Error::from(std::io::Error::other("/var/lib/lily/images/12: disk full")).with_field("image", "at /var/lib");
+        let body = body(&e).await;
+        assert_eq!(body["status"], 500);
+        assert_eq!(body["code"], "INTERNAL");
+        assert_eq!(body["detail"], INTERNAL_DETAIL);
+        assert!(body.get("errors").is_none());
+
+        let e = Error::new(ErrorKind::Unavailable, "scylla 10.0.0.3:9042 timed out").with_retry_after(5);
+        let res = e.error_response();
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "5");
+        let body = self::body(&e).await;
+        assert_eq!(body["status"], 503);
+        assert_eq!(body["detail"], UNAVAILABLE_DETAIL);
     }
-}
\ No newline at end of file
+}
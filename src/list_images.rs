@@ -0,0 +1,269 @@
+use crate::auth::AuthSession;
+use crate::catalog::{ImageCatalog, ImageRecord, ImageStatus};
+use crate::error::Error;
+use crate::safe_path::UserId;
+use crate::unique::uuid_time;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+pub struct ListQuery {
+    /// `nextCursor` of the previous page; only older images are returned.
+    cursor: Option<Uuid>,
+    limit: Option<usize>,
+    /// Inclusive RFC 3339 bounds on the upload time.
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    ext: Option<String>,
+}
+
+/// One file of a variant.
+#[derive(Serialize)]
+struct FormatItem {
+    ext: String,
+    #[serde(rename = "type")]
+    mime: String,
+    url: String,
+    bytes: u64,
+}
+
+/// A variant as reported by the upload and listing responses. `ext`,
+/// `url` and `bytes` are those of its first format.
+#[derive(Serialize)]
+pub(crate) struct VariantItem {
+    name: String,
+    suffix: String,
+    ext: String,
+    density: u32,
+    url: String,
+    width: u32,
+    height: u32,
+    bytes: u64,
+    formats: Vec<FormatItem>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize)]
+struct ImageItem {
+    imgName: String,
+    imgExt: String,
+    createdAt: Option<String>,
+    originalName: String,
+    variants: Vec<VariantItem>,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize)]
+struct ListResponse {
+    images: Vec<ImageItem>,
+    nextCursor: Option<String>,
+}
+
+/// The variants of `record`, with the files sharing a suffix grouped
+/// as the formats of one variant.
+pub(crate) fn variant_items(record: &ImageRecord) -> Vec<VariantItem> {
+    let mut items: Vec<VariantItem> = vec![];
+    for v in &record.variants {
+        let ext = record.variant_ext(v);
+        let format = FormatItem {
+            ext: ext.to_owned(),
+            mime: actix_files::file_extension_to_mime(ext).to_string(),
+            url: format!("/getimage/{}/{}", record.user_id, record.variant_file_name(v)),
+            bytes: v.bytes,
+        };
+        match items.iter_mut().find(|item| item.suffix == v.suffix) {
+            Some(item) => item.formats.push(format),
+            None => items.push(VariantItem {
+                name: v.name.clone(),
+                suffix: v.suffix.clone(),
+                ext: format.ext.clone(),
+                density: v.density,
+                url: format.url.clone(),
+                width: v.width,
+                height: v.height,
+                bytes: v.bytes,
+                formats: vec![format],
+            }),
+        }
+    }
+    items
+}
+
+fn image_item(record: ImageRecord) -> ImageItem {
+    ImageItem {
+        createdAt: uuid_time(&record.image_id).map(|t| t.to_rfc3339_opts(SecondsFormat::Millis, true)),
+        imgName: record.image_id.to_string(),
+        variants: variant_items(&record),
+        imgExt: record.ext,
+        originalName: record.original_filename,
+    }
+}
+
+/// `GET /users/{userId}/images`: the user's active images, newest first,
+/// for that user or an admin; original names may be private.
+/// Pages are read from the catalog in upload order, so a date range ends
+/// the scan as soon as it reaches an image older than `from`.
+pub async fn list_images(req: HttpRequest, catalog: web::Data<dyn ImageCatalog>, path: web::Path<String>, query: web::Query<ListQuery>) -> Result<HttpResponse, Error> {
+    let user_id = UserId::parse(&path)?.get();
+    req.user_info()?.owner(Some(user_id), "list_images")?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let ext = query.ext.as_ref().map(|e| e.trim_start_matches('.').to_lowercase());
+
+    let mut images = vec![];
+    let mut before = query.cursor;
+    let mut next_cursor = None;
+    'pages: loop {
+        let page = catalog.list(user_id, before, limit).await?;
+        let exhausted = page.len() < limit;
+        for record in page {
+            before = Some(record.image_id);
+            let created = uuid_time(&record.image_id);
+            if let (Some(from), Some(created)) = (query.from, created) {
+                if created < from {
+                    break 'pages;
+                }
+            }
+            if let (Some(to), Some(created)) = (query.to, created) {
+                if created > to {
+                    continue;
+                }
+            }
+            if record.status != ImageStatus::Active {
+                continue;
+            }
+            if ext.as_ref().is_some_and(|ext| *ext != record.ext.to_lowercase()) {
+                continue;
+            }
+            images.push(image_item(record));
+            if images.len() == limit {
+                next_cursor = before.map(|id| id.to_string());
+                break 'pages;
+            }
+        }
+        if exhausted {
+            break;
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(ListResponse {
+        images,
+        nextCursor: next_cursor,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{Role, AUTHUSER};
+    use crate::catalog::EmbeddedCatalog;
+
+    use std::sync::Arc;
+    use actix_web::dev::Service;
+    use actix_web::test::{call_service, init_service, read_body_json, TestRequest};
+    use actix_web::{App, HttpMessage};
+    use uuid::v1::{Context, Timestamp};
+
+    /// A v1 id made `hours` after 2024-01-01T00:00:00Z.
+    fn id_at(hours: u64) -> Uuid {
+        let ts = Timestamp::from_unix(Context::new(0), 1_704_067_200 + hours * 3600, 0);
+        Uuid::new_v1(ts, &[1; 6]).unwrap()
+    }
+
+    /// User 12's images, one an hour for `hours` hours: every third a PNG,
+    /// every fifth trashed. User 13 has one image each hour too.
+    async fn catalog(hours: u64) -> (Arc<dyn ImageCatalog>, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let catalog: Arc<dyn ImageCatalog> = Arc::new(EmbeddedCatalog::open(&dir.path().join("catalog")).unwrap());
+        for hour in 0..hours {
+            for user_id in [12, 13] {
+                let record = ImageRecord {
+                    user_id,
+                    image_id: id_at(hour),
+                    ext: if hour % 3 == 0 { "png" } else { "jpg" }.to_owned(),
+                    variants: vec![],
+                    content_hash: String::new(),
+                    original_filename: format!("{}.jpg", hour),
+                    status: if hour % 5 == 4 { ImageStatus::Trashed } else { ImageStatus::Active },
+                    crop: None,
+                };
+                catalog.put(&record).await.unwrap();
+            }
+        }
+        (catalog, dir)
+    }
+
+    /// Follows `nextCursor` from `uri` as user 12, checking that every
+    /// page is full but the last, and returns the `imgName`s in order.
+    async fn traverse(catalog: Arc<dyn ImageCatalog>, uri: &str, limit: usize) -> Vec<Uuid> {
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::from(catalog))
+                .route("/users/{userId}/images", web::get().to(list_images))
+                .wrap_fn(|req, srv| {
+                    let user = AUTHUSER { userId: 12, fname: String::new(), lname: String::new(), email: String::new(), role: Role::User };
+                    req.extensions_mut().insert(user);
+                    srv.call(req)
+                }),
+        )
+        .await;
+        let separator = if uri.contains('?') { '&' } else { '?' };
+        let mut names = vec![];
+        let mut cursor: Option<String> = None;
+        loop {
+            let page_uri = match &cursor {
+                Some(cursor) => format!("{}{}limit={}&cursor={}", uri, separator, limit, cursor),
+                None => format!("{}{}limit={}", uri, separator, limit),
+            };
+            let res = call_service(&app, TestRequest::get().uri(&page_uri).to_request()).await;
+            assert_eq!(res.status(), actix_web::http::StatusCode::OK, "{}", page_uri);
+            let page: serde_json::Value = read_body_json(res).await;
+            let images = page["images"].as_array().unwrap();
+            names.extend(images.iter().map(|image| Uuid::parse_str(image["imgName"].as_str().unwrap()).unwrap()));
+            match page["nextCursor"].as_str() {
+                Some(next) => {
+                    assert_eq!(images.len(), limit, "{}", page_uri);
+                    cursor = Some(next.to_owned());
+                }
+                None => return names,
+            }
+        }
+    }
+
+    fn expected(hours: impl DoubleEndedIterator<Item = u64>, keep: impl Fn(u64) -> bool) -> Vec<Uuid> {
+        hours.rev().filter(|hour| hour % 5 != 4 && keep(*hour)).map(id_at).collect()
+    }
+
+    #[actix_web::test]
+    async fn cursor_pages_cover_every_active_image_once() {
+        let (catalog, _dir) = catalog(23).await;
+        let all = expected(0..23, |_| true);
+        assert_eq!(all.len(), 19);
+        for limit in [1, 2, 4, 19, 100] {
+            assert_eq!(traverse(catalog.clone(), "/users/12/images", limit).await, all, "limit {}", limit);
+        }
+    }
+
+    #[actix_web::test]
+    async fn filters_by_upload_time_and_ext() {
+        let (catalog, _dir) = catalog(23).await;
+        let uri = "/users/12/images?from=2024-01-01T05:00:00Z&to=2024-01-01T15:00:00Z";
+        for limit in [2, 3, 100] {
+            assert_eq!(traverse(catalog.clone(), uri, limit).await, expected(5..16, |_| true), "limit {}", limit);
+        }
+        let png = expected(0..23, |hour| hour % 3 == 0);
+        for uri in ["/users/12/images?ext=png", "/users/12/images?ext=.PNG"] {
+            assert_eq!(traverse(catalog.clone(), uri, 2).await, png, "{}", uri);
+        }
+        let uri = "/users/12/images?ext=jpg&from=2024-01-01T10:00:00%2B00:00";
+        assert_eq!(traverse(catalog.clone(), uri, 3).await, expected(10..23, |hour| hour % 3 != 0));
+        assert!(traverse(catalog.clone(), "/users/12/images?ext=webp", 5).await.is_empty());
+        // a range after every upload has nothing in it
+        assert!(traverse(catalog, "/users/12/images?from=2025-01-01T00:00:00Z", 5).await.is_empty());
+    }
+}
@@ -6,20 +6,35 @@ mod auth;
 mod middleware;
 mod postman;
 mod delete_image;
+mod reprocess;
+mod list_images;
+mod config;
+mod storage;
+mod serve;
+mod negotiate;
+mod catalog;
+mod safe_path;
+mod avif;
+mod workers;
+mod resize;
 
-use std::env;
 use anyhow::Result;
 use actix_cors::Cors;
-use actix_web::{App as ActixApp, HttpServer};
+use actix_web::{web, App as ActixApp, HttpServer};
 use actix_redis::RedisSession;
-
-pub(crate) static PATH: &str = "/home/sankar/bin/images";
-pub(crate) static TRASH: &str = "/home/sankar/trash";
+use crate::config::Config;
 
 #[actix_web::main]
 async fn main() -> Result<()> {
-    let host = env::var("HOST").unwrap();
-    let port = env::var("PORT").unwrap();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    let config = Config::load()?;
+    let bind_address = config.bind_address();
+    let storage: web::Data<dyn storage::Storage> = web::Data::from(storage::from_config(&config));
+    let catalog: web::Data<dyn catalog::ImageCatalog> = web::Data::from(catalog::from_config(&config).await?);
+    let jwt = web::Data::new(auth::JwtVerifier::from_config(&config.auth.jwt)?);
+    let workers = web::Data::new(workers::WorkerPool::new(&config.workers)?);
+    let avif = web::Data::new(avif::AvifEncoder::new(&config.avif)?);
+    let data = web::Data::new(config);
 
     HttpServer::new(move || {
         let cors = Cors::default()
@@ -27,19 +42,26 @@ async fn main() -> Result<()> {
               .allow_any_method()
               .allow_any_header()
               .supports_credentials();
+        let session = &data.session;
 
         ActixApp::new()
+            .app_data(data.clone())
+            .app_data(storage.clone())
+            .app_data(catalog.clone())
+            .app_data(jwt.clone())
+            .app_data(workers.clone())
+            .app_data(avif.clone())
             .wrap(cors)
             .wrap(
-                RedisSession::new("127.0.0.1:6379", &[0; 32])
-                .cookie_name("lily-session")
+                RedisSession::new(session.redis.as_str(), session.key.as_bytes())
+                .cookie_name(&session.cookie_name)
                 .cookie_http_only(true)
-                .ttl(86400)
+                .ttl(session.ttl)
             )
             .configure(route::routes)
     })
-    .bind(format!("{}:{}", host, port))?
+    .bind(bind_address)?
     .run()
     .await?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -2,12 +2,18 @@ use std::pin::Pin;
 use std::cell::RefCell;
 use std::rc::Rc;
 use actix_service::{Service, Transform};
-use actix_web::{dev::ServiceRequest, dev::ServiceResponse, Error, error::ErrorUnauthorized};
+use actix_web::{dev::ServiceRequest, dev::ServiceResponse, http::header, web, Error, HttpMessage};
 use futures::future::{ok, Ready};
 use futures::Future;
 use actix_session::UserSession;
+use crate::auth::{unauthenticated, AuthSession, JwtVerifier};
 
 
+/// Rejects requests without a signed-in user with 401 and otherwise puts
+/// the `AUTHUSER` into the request extensions, where handlers read it
+/// back through `AuthSession` on `HttpRequest`. A request carrying
+/// `Authorization: Bearer` is judged by its token alone; everything else
+/// falls back to the Redis session.
 #[derive(Debug, Clone)]
 pub struct Authentication;
 
@@ -28,6 +34,16 @@ where
     }
 }
 
+fn bearer_token(req: &ServiceRequest) -> Option<&str> {
+    let value = req.headers().get(header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
 pub struct AuthenticationMiddleware<S> {
     service: Rc<RefCell<S>>,
 }
@@ -45,16 +61,101 @@ where
     fn call(&self, req: ServiceRequest) -> Self::Future {
         let srv = self.service.clone();
         Box::pin(async move {
-            let session = req.get_session();
-            match session.get::<String>("AUTH_ID") { 
-                Ok(_) => {
+            let user = match bearer_token(&req) {
+                Some(token) => match req.app_data::<web::Data<JwtVerifier>>() {
+                    Some(verifier) => verifier.verify(token),
+                    None => Err(unauthenticated()),
+                },
+                None => req.get_session().user_info(),
+            };
+            match user {
+                Ok(user) => {
+                    req.extensions_mut().insert(user);
                     let res_fut = srv.call(req);
-                    return res_fut.await;
+                    res_fut.await
                 },
-                Err(_) => {
-                    return Err(ErrorUnauthorized("unauthorized")); 
+                Err(e) => {
+                    Err(e.into())
                 }
             }
         })
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::{AUTHUSER, AUTH_USER, Role};
+    use crate::config::JwtConfig;
+    use actix_session::Session;
+    use actix_web::http::StatusCode;
+    use actix_web::test::{init_service, read_body, try_call_service, TestRequest};
+    use actix_web::{App, HttpRequest};
+
+    const SECRET: &str = "middleware-tests";
+    /// Test header whose user id is put in the session ahead of the
+    /// middleware, as the Redis session would be.
+    const SESSION_USER: &str = "x-session-user";
+
+    fn bearer(user_id: i32) -> String {
+        let claims = serde_json::json!({ "sub": user_id.to_string(), "exp": 4_102_444_800u64 });
+        let key = jsonwebtoken::EncodingKey::from_secret(SECRET.as_bytes());
+        format!("Bearer {}", jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &key).unwrap())
+    }
+
+    async fn me(req: HttpRequest) -> Result<String, crate::error::Error> {
+        req.user_info().map(|user| user.userId.to_string())
+    }
+
+    /// Status of `req` and, when let through, the user id it was served as.
+    async fn authenticate(req: TestRequest, verifier: bool) -> (StatusCode, String) {
+        let config = JwtConfig { hs256_secret: Some(SECRET.to_owned()), ..JwtConfig::default() };
+        let mut app = App::new();
+        if verifier {
+            app = app.app_data(web::Data::new(JwtVerifier::from_config(&config).unwrap()));
+        }
+        let app = init_service(
+            app.route("/me", web::get().to(me))
+                .wrap(Authentication)
+                .wrap_fn(|mut req, srv| {
+                    let user_id = req.headers().get(SESSION_USER).and_then(|v| v.to_str().ok()?.parse().ok());
+                    if let Some(user_id) = user_id {
+                        let user = AUTHUSER { userId: user_id, fname: String::new(), lname: String::new(), email: String::new(), role: Role::User };
+                        let value = serde_json::to_string(&serde_json::to_string(&user).unwrap()).unwrap();
+                        Session::set_session(&mut req, [(AUTH_USER.to_owned(), value)]);
+                    }
+                    srv.call(req)
+                }),
+        )
+        .await;
+        match try_call_service(&app, req.uri("/me").to_request()).await {
+            Ok(res) => (res.status(), String::from_utf8(read_body(res).await.to_vec()).unwrap()),
+            Err(e) => (e.as_response_error().status_code(), String::new()),
+        }
+    }
+
+    #[actix_web::test]
+    async fn bearer_token_is_used_when_present() {
+        let req = TestRequest::get().insert_header((header::AUTHORIZATION, bearer(12)));
+        assert_eq!(authenticate(req, true).await, (StatusCode::OK, "12".to_owned()));
+        let req = TestRequest::get().insert_header((header::AUTHORIZATION, bearer(12))).insert_header((SESSION_USER, "7"));
+        assert_eq!(authenticate(req, true).await, (StatusCode::OK, "12".to_owned()));
+    }
+
+    #[actix_web::test]
+    async fn bad_bearer_token_does_not_fall_back_to_the_session() {
+        let req = TestRequest::get().insert_header((header::AUTHORIZATION, "Bearer nope")).insert_header((SESSION_USER, "7"));
+        assert_eq!(authenticate(req, true).await.0, StatusCode::UNAUTHORIZED);
+        // nor does a good one the server cannot check
+        let req = TestRequest::get().insert_header((header::AUTHORIZATION, bearer(12))).insert_header((SESSION_USER, "7"));
+        assert_eq!(authenticate(req, false).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn session_is_used_without_a_bearer_token() {
+        let req = TestRequest::get().insert_header((SESSION_USER, "7"));
+        assert_eq!(authenticate(req, true).await, (StatusCode::OK, "7".to_owned()));
+        let req = TestRequest::get().insert_header((header::AUTHORIZATION, "Basic dXNlcjpwYXNz")).insert_header((SESSION_USER, "7"));
+        assert_eq!(authenticate(req, true).await, (StatusCode::OK, "7".to_owned()));
+        assert_eq!(authenticate(TestRequest::get(), true).await.0, StatusCode::UNAUTHORIZED);
+    }
+}
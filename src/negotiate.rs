@@ -0,0 +1,221 @@
+use crate::catalog::{ImageCatalog, ImageRecord, ImageStatus, VariantRecord};
+use crate::error::{Error, ErrorKind};
+use crate::safe_path::{image_id, UserId};
+use crate::config::Config;
+use crate::serve::{download_name, serve_object, ServeOptions, ServeQuery};
+use crate::storage::Storage;
+
+use actix_web::http::header::{self, Header, HeaderValue};
+use actix_web::{web, HttpRequest, HttpResponse};
+
+/// Formats served only to clients that name them in `Accept`, best first.
+static PREFERRED: [(&str, &str); 2] = [("avif", "image/avif"), ("webp", "image/webp")];
+
+/// Whether `Accept` lists `mime` itself with a non-zero quality.
+/// Wildcards do not count: browsers send `*/*` whether or not they can
+/// decode AVIF or WebP.
+fn accepts(req: &HttpRequest, mime: &str) -> bool {
+    header::Accept::parse(req).is_ok_and(|accept| {
+        accept
+            .iter()
+            .any(|item| item.item.essence_str() == mime && item.quality > header::Quality::ZERO)
+    })
+}
+
+/// Picks the file among `candidates`, the formats of one variant: AVIF,
+/// then WebP, if accepted, else the first other format, else whatever
+/// exists.
+fn pick<'a>(req: &HttpRequest, record: &ImageRecord, candidates: &[&'a VariantRecord]) -> Option<&'a VariantRecord> {
+    let with_ext = |ext: &str| candidates.iter().copied().find(|v| record.variant_ext(v) == ext);
+    PREFERRED
+        .iter()
+        .filter(|(_, mime)| accepts(req, mime))
+        .find_map(|(ext, _)| with_ext(ext))
+        .or_else(|| {
+            candidates
+                .iter()
+                .copied()
+                .find(|v| PREFERRED.iter().all(|(ext, _)| record.variant_ext(v) != *ext))
+        })
+        .or_else(|| candidates.first().copied())
+}
+
+/// `variant` is a profile name or suffix, optionally with a density,
+/// e.g. `md`, `md@2x` or `720@2x`.
+fn matches(v: &VariantRecord, variant: &str) -> bool {
+    let (base, density) = match variant.split_once('@') {
+        Some((base, density)) => (base, density.strip_suffix('x').and_then(|d| d.parse().ok())),
+        None => (variant, Some(1)),
+    };
+    density == Some(v.density) && (v.name == base || v.suffix == variant)
+}
+
+/// `GET /i/{userId}/{imgName}/{variant}`: serves the best encoding of a
+/// variant the client accepts, inline unless `?download=1` is set.
+pub async fn serve_variant(req: HttpRequest, storage: web::Data<dyn Storage>, catalog: web::Data<dyn ImageCatalog>, config: web::Data<Config>, query: web::Query<ServeQuery>, path: web::Path<(String, String, String)>) -> Result<HttpResponse, Error> {
+    let user_id = UserId::parse(&path.0)?;
+    let id = image_id(&path.1)?;
+    let not_found = || Error::new(ErrorKind::NotFound, "image not found.");
+    let record = catalog
+        .get(user_id.get(), id)
+        .await?
+        .filter(|record| record.status == ImageStatus::Active)
+        .ok_or_else(not_found)?;
+    let candidates: Vec<&VariantRecord> = record.variants.iter().filter(|v| matches(v, &path.2)).collect();
+    let variant = pick(&req, &record, &candidates).ok_or_else(not_found)?;
+
+    let file_name = record.variant_file_name(variant);
+    let key = format!("{}/{}", user_id, file_name);
+    let options = ServeOptions {
+        download: query.download().then(|| download_name(Some(&record.original_filename), &file_name)),
+        cache_control: &config.cache.aliases,
+        hash: Some(variant.hash.as_str()).filter(|hash| !hash.is_empty()),
+    };
+    let mut res = serve_object(&req, storage.as_ref(), &key, options).await?;
+    res.headers_mut().insert(header::VARY, HeaderValue::from_static("Accept"));
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::EmbeddedCatalog;
+    use crate::storage::MemoryStorage;
+
+    use std::sync::Arc;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{http::StatusCode, App};
+    use uuid::Uuid;
+
+    fn variant(name: &str, suffix: &str, ext: &str, density: u32) -> VariantRecord {
+        VariantRecord {
+            name: name.to_owned(),
+            suffix: suffix.to_owned(),
+            ext: ext.to_owned(),
+            density,
+            width: 720 * density,
+            height: 540 * density,
+            bytes: 10,
+            hash: String::new(),
+        }
+    }
+
+    /// `md` and `md@2x` (suffixes `720` and `720@2x`) as JPEG, WebP and
+    /// AVIF.
+    fn record() -> ImageRecord {
+        let mut variants = vec![];
+        for (suffix, density) in [("720", 1), ("720@2x", 2)] {
+            for ext in ["", "webp", "avif"] {
+                variants.push(variant("md", suffix, ext, density));
+            }
+        }
+        ImageRecord {
+            user_id: 12,
+            image_id: Uuid::parse_str("6f1c7a3e-3b1d-11ef-9a4b-0242ac120002").unwrap(),
+            ext: "jpg".to_owned(),
+            variants,
+            content_hash: String::new(),
+            original_filename: "photo.jpg".to_owned(),
+            status: ImageStatus::Active,
+            crop: None,
+        }
+    }
+
+    /// Extension of the file `pick` serves for `accept`.
+    fn picked(accept: Option<&str>, record: &ImageRecord, exts: &[&str]) -> String {
+        let mut req = TestRequest::get();
+        if let Some(accept) = accept {
+            req = req.insert_header((header::ACCEPT, accept));
+        }
+        let candidates: Vec<&VariantRecord> = record
+            .variants
+            .iter()
+            .filter(|v| v.density == 1 && exts.contains(&record.variant_ext(v)))
+            .collect();
+        let picked = pick(&req.to_http_request(), record, &candidates).unwrap();
+        record.variant_ext(picked).to_owned()
+    }
+
+    #[test]
+    fn pick_prefers_avif_then_webp_then_the_original() {
+        let record = record();
+        let all = ["jpg", "webp", "avif"];
+        assert_eq!(picked(Some("image/avif,image/webp,*/*"), &record, &all), "avif");
+        assert_eq!(picked(Some("image/webp,image/avif"), &record, &all), "avif");
+        assert_eq!(picked(Some("image/webp,*/*"), &record, &all), "webp");
+        assert_eq!(picked(Some("image/avif;q=0,image/webp"), &record, &all), "webp");
+        assert_eq!(picked(Some("image/jpeg"), &record, &all), "jpg");
+        // an accepted format that was not made falls through to the next
+        assert_eq!(picked(Some("image/avif,image/webp"), &record, &["jpg", "webp"]), "webp");
+        assert_eq!(picked(Some("image/avif"), &record, &["jpg", "webp"]), "jpg");
+        // with only unaccepted formats stored, any is better than none
+        assert_eq!(picked(Some("image/jpeg"), &record, &["avif"]), "avif");
+    }
+
+    #[test]
+    fn wildcards_do_not_select_avif_or_webp() {
+        let record = record();
+        let all = ["jpg", "webp", "avif"];
+        for accept in [None, Some("*/*"), Some("image/*"), Some("image/*,*/*;q=0.8"), Some("garbage")] {
+            assert_eq!(picked(accept, &record, &all), "jpg", "{:?}", accept);
+        }
+    }
+
+    #[test]
+    fn matches_names_and_suffixes_with_densities() {
+        let md = variant("md", "720", "", 1);
+        let md2x = variant("md", "720@2x", "", 2);
+        for (name, one, two) in [
+            ("md", true, false),
+            ("720", true, false),
+            ("md@1x", true, false),
+            ("md@2x", false, true),
+            ("720@2x", false, true),
+            ("md@3x", false, false),
+            ("md@x", false, false),
+            ("lg", false, false),
+            ("72", false, false),
+        ] {
+            assert_eq!(matches(&md, name), one, "{}", name);
+            assert_eq!(matches(&md2x, name), two, "{}", name);
+        }
+    }
+
+    #[actix_web::test]
+    async fn serve_variant_varies_on_accept() {
+        let dir = tempfile::tempdir().unwrap();
+        let catalog: Arc<dyn ImageCatalog> = Arc::new(EmbeddedCatalog::open(&dir.path().join("catalog")).unwrap());
+        let storage: Arc<dyn Storage> = Arc::new(MemoryStorage::default());
+        let record = record();
+        catalog.put(&record).await.unwrap();
+        for v in &record.variants {
+            let key = format!("12/{}", record.variant_file_name(v));
+            storage.put_bytes(&key, record.variant_ext(v).as_bytes().to_vec().into()).await.unwrap();
+        }
+        let app = init_service(
+            App::new()
+                .app_data(web::Data::from(storage))
+                .app_data(web::Data::from(catalog))
+                .app_data(web::Data::new(Config::default()))
+                .route("/i/{userId}/{imgName}/{variant}", web::get().to(serve_variant)),
+        )
+        .await;
+
+        let uri = format!("/i/12/{}/md@2x", record.image_id);
+        let req = TestRequest::get().uri(&uri).insert_header((header::ACCEPT, "image/avif,*/*")).to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "Accept");
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "image/avif");
+        assert_eq!(read_body(res).await, "avif");
+
+        let req = TestRequest::get().uri(&uri).insert_header((header::ACCEPT, "*/*")).to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(res.headers().get(header::VARY).unwrap(), "Accept");
+        assert_eq!(read_body(res).await, "jpg");
+
+        let uri = format!("/i/12/{}/sm", record.image_id);
+        let res = call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
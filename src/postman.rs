@@ -1,16 +1,18 @@
 use crate::unique::time_uuid;
-use crate::error::Error;
+use crate::error::{Error, ErrorKind};
+use crate::config::{Config, LimitsConfig, ResizeFilter};
+use crate::safe_path::image_ext;
+use crate::upload::{check_request_size, decode, too_large};
 
-use actix_web::{HttpResponse, web};
+use actix_web::{HttpRequest, HttpResponse, web};
 use actix_multipart::{Multipart, Field};
 use futures::{StreamExt, TryStreamExt};
-use std::{io::Write, fs};
+use std::{io::Write, fs, path::{Path, PathBuf}};
 use serde::{Deserialize, Serialize};
-use image::{self, imageops::{self, FilterType}};
-
-static PATH: &str = "/home/sankar/Pictures/test/";
+use image::{self, imageops, ImageFormat};
 
 #[derive(Serialize, Deserialize)]
+#[allow(dead_code)]
 pub struct UserRequest {
     user_id: String,
 }
@@ -28,78 +30,111 @@ struct MetaData {
     height: u32
 }
 
-async fn create_file(p: &String) -> Result<std::fs::File, std::io::Error> {
-    std::fs::File::create(p)
-}
-
-async fn save_image(field: &mut Field, _: &MetaData) -> Result<(String,String), Error> {
+async fn save_image(field: &mut Field, _: &MetaData, dir: &Path, limits: &LimitsConfig) -> Result<(PathBuf, PathBuf), Error> {
     let content_type = field.content_disposition();
     let meta_filename = content_type.get_filename();
     let meta_filename = match meta_filename {
         Some(r) => r,
         None => {
-            return Err(Error::from("could not get metadata filename.").into());
+            return Err(Error::new(ErrorKind::InvalidRequest, "could not get metadata filename."));
         }
     };
     let split_filename: Vec<&str> = meta_filename.split('.').collect();
     if split_filename.len() != 2 {
-        return Err(Error::from("error in splitting filename.").into());
+        return Err(Error::new(ErrorKind::InvalidRequest, "error in splitting filename."));
     }
 
     let new_filename = time_uuid().to_string();
-    let ext = split_filename[1].clone();
+    let ext = image_ext(split_filename[1])?;
 
-    let img_tmp_path = format!("{}/{}.tmp.{}", PATH, new_filename, ext);
+    let img_tmp_path = dir.join(format!("{}.tmp.{}", new_filename, ext));
     // let tmp_image_clone = tmp_image.clone();
-    let crp_img_path = format!("{}/{}.{}", PATH, new_filename, &ext);
+    let crp_img_path = dir.join(format!("{}.{}", new_filename, ext));
     
     // File::create is blocking operation, use threadpool
-    let mut f = create_file(&img_tmp_path).await;
+    let path = img_tmp_path.clone();
+    let f = web::block(move || std::fs::File::create(path)).await??;
+    let saved = match write_chunks(field, f, limits.max_image_bytes).await {
+        Ok(0) => Err(Error::new(ErrorKind::InvalidRequest, "Could not save image.")),
+        other => other,
+    };
+    if let Err(e) = saved {
+        let path = img_tmp_path.clone();
+        let _ = web::block(move || fs::remove_file(path)).await;
+        return Err(e);
+    }
+    Ok((img_tmp_path, crp_img_path))
+}
 
-    let mut done = false;
+/// Writes `field` to `f`, refusing it once it is over `max` bytes.
+async fn write_chunks(field: &mut Field, mut f: fs::File, max: u64) -> Result<u64, Error> {
+    let mut received = 0;
     // Field in turn is stream of *Bytes* object
     while let Some(chunk) = field.next().await {
         let data = chunk?;
+        received += data.len() as u64;
+        if received > max {
+            return Err(too_large("image", max));
+        }
         // filesystem operations are blocking, we have to use threadpool
         f = web::block(move || -> Result<std::fs::File, std::io::Error> {
-            let mut g = f?; 
-            g.write_all(&data)?;
-            Ok(g)
-        }).await?;
-        done = true;
-    }
-    if done == false {
-        return Err(Error::from("Could not save image.").into());
+            f.write_all(&data)?;
+            Ok(f)
+        }).await??;
     }
-    Ok((img_tmp_path, crp_img_path))
+    Ok(received)
 }
 
-async fn get_value(field: &mut Field) -> Result<Option<String>, Error> {
-    let mut value: Option<String> = None;
+async fn get_value(field: &mut Field, limits: &LimitsConfig) -> Result<Option<String>, Error> {
+    let mut data = Vec::new();
     while let Some(chunk) = field.next().await {
-        let data = chunk?;
-        let v = String::from_utf8_lossy(&data).to_string();
-        value = Some(v);
+        data.extend_from_slice(&chunk?);
+        if data.len() as u64 > limits.max_metadata_bytes {
+            return Err(too_large("metadata", limits.max_metadata_bytes));
+        }
     }
-    Ok(value)
+    if data.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&data).into_owned()))
 }
 
 
-fn crop_image(paths: &(String, String), metadata: &MetaData) -> Result<Option<String>, Error> {
-    let mut img = image::open(&paths.0)?;
-    let subimg = imageops::crop(&mut img, metadata.x.clone(), metadata.y.clone(), metadata.width.clone(), metadata.height.clone());
+/// Decodes with the upload `limits`, so the test route refuses the
+/// same images as `/upload` before decoding them.
+fn crop_image(paths: &(PathBuf, PathBuf), metadata: &MetaData, limits: &LimitsConfig) -> Result<Option<String>, Error> {
+    let format = ImageFormat::from_path(&paths.0)?;
+    let source = fs::read(&paths.0)?;
+    let decoded = decode(&source, format, limits);
+    drop(source);
+    let mut img = match decoded {
+        Ok(img) => img,
+        Err(e) => {
+            fs::remove_file(&paths.0)?;
+            return Err(e);
+        }
+    };
+    let subimg = imageops::crop(&mut img, metadata.x, metadata.y, metadata.width, metadata.height);
     let d = subimg.to_image();
-    let x = image::imageops::resize(&d, metadata.width.clone()/100*50, metadata.height.clone()/100*50, FilterType::Nearest);
+    let x = image::imageops::resize(&d, metadata.width/100*50, metadata.height/100*50, ResizeFilter::default().into());
     x.save(&paths.1)?;
-    fs::remove_file(paths.0.clone())?;
-    Ok(Some(paths.1.clone()))
+    fs::remove_file(&paths.0)?;
+    Ok(Some(paths.1.display().to_string()))
 }
 
 // NOTE: image wont upload from postman if you set Content-Type: multipart/form-data
 // Postman->Body->binary
-pub async fn upload_image(mut payload: Multipart) -> Result<HttpResponse, Error> {
+pub async fn upload_image(req: HttpRequest, config: web::Data<Config>, mut payload: Multipart) -> Result<HttpResponse, Error> {
+    let dir = match &config.paths.test {
+        Some(dir) => dir,
+        None => {
+            return Err(Error::new(ErrorKind::NotFound, "paths.test is not configured."));
+        }
+    };
+    let limits = config.limits;
+    check_request_size(&req, &limits)?;
 
-    let mut image_data: Option<(String, String)> = None;
+    let mut image_data: Option<(PathBuf, PathBuf)> = None;
     let mut metadata: Option<MetaData> = None;
 
     while let Ok(Some(mut field)) = payload.try_next().await {
@@ -107,21 +142,23 @@ pub async fn upload_image(mut payload: Multipart) -> Result<HttpResponse, Error>
         let name = match content_disposition.get_name() {
             Some(name) => name,
             None => {
-                return Err(Error::from("Cannot get name").into());
+                return Err(Error::new(ErrorKind::InvalidRequest, "Cannot get name"));
             }
         };
         match name {
             "metadata" => {
-                let x = get_value(&mut field).await?;
+                let x = get_value(&mut field, &limits).await?;
                 println!("metadata: {:?}", &x);
                 if let Some(x) = x {
-                    metadata = Some(serde_json::from_str(&x)?);
+                    metadata = Some(serde_json::from_str(&x).map_err(|e| {
+                        Error::new(ErrorKind::InvalidRequest, "metadata is not valid JSON.").with_field("metadata", e.to_string())
+                    })?);
                 } 
                 println!("metadata_one: {:?}", &metadata);
             },
             "image" => {
                 if let Some(metadata) = &metadata {
-                    image_data = Some(save_image(&mut field, metadata).await?);
+                    image_data = Some(save_image(&mut field, metadata, dir, &limits).await?);
                 }
             },
             _ => {}
@@ -131,12 +168,12 @@ pub async fn upload_image(mut payload: Multipart) -> Result<HttpResponse, Error>
     let mut image_url: Option<String> = None;
     if let Some(paths) = image_data {
         if let Some(metadata) = metadata {
-            image_url = crop_image(&paths, &metadata)?;
+            image_url = web::block(move || crop_image(&paths, &metadata, &limits)).await??;
         }
     }
 
     if image_url.is_none() {
-        return Err(Error::from("Could not save image"));
+        return Err(Error::new(ErrorKind::InvalidRequest, "Could not save image"));
     }
     Ok(HttpResponse::Ok().json(UploadResponse {
         image_url: image_url.unwrap()
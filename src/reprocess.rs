@@ -0,0 +1,174 @@
+use crate::auth::AuthSession;
+use crate::avif::{AvifEncoder, PendingVariant, AVIF_EXT};
+use crate::catalog::{Crop, ImageCatalog, ImageRecord, ImageStatus, VariantRecord};
+use crate::config::Config;
+use crate::error::{Error, ErrorKind};
+use crate::safe_path::{image_id, ImageName, UserId};
+use crate::storage::Storage;
+use crate::upload::{crop_image, store_variants, variant_file_name, UploadResponse};
+use crate::workers::WorkerPool;
+
+use actix_web::{web, HttpRequest, HttpResponse};
+use std::collections::BTreeMap;
+use std::io::Cursor;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Catalog records read per page by `reprocess_user`.
+const PAGE_SIZE: usize = 50;
+
+#[derive(Serialize)]
+struct ReprocessResponse {
+    reprocessed: usize,
+}
+
+/// Regenerates the variants of `record` with the current profiles and
+/// removes files of variants that are no longer configured. Records
+/// without a kept source are rebuilt from their largest variant. Returns
+/// the updated record and the AVIF variants still to be encoded.
+async fn reprocess(storage: &dyn Storage, catalog: &dyn ImageCatalog, workers: &WorkerPool, config: &Config, mut record: ImageRecord) -> Result<(ImageRecord, Vec<PendingVariant>), Error> {
+    let user_id = record.user_id;
+    let (source, source_ext, crop) = match (record.crop, record.source_file_name()) {
+        (Some(crop), Some(source)) => (storage.get(&format!("{}/{}", user_id, source)).await?, record.ext.clone(), crop),
+        _ => {
+            let largest = record
+                .variants
+                .iter()
+                .filter(|v| v.ext != AVIF_EXT)
+                .max_by_key(|v| u64::from(v.width) * u64::from(v.height))
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, "image has no files to reprocess."))?;
+            let data = storage.get(&format!("{}/{}", user_id, record.variant_file_name(largest))).await?;
+            let crop = Crop { x: 0, y: 0, width: largest.width, height: largest.height };
+            (data, record.variant_ext(largest).to_owned(), crop)
+        }
+    };
+    let profiles = config.variants.clone();
+    let limits = config.limits;
+    let engine = config.resize.engine;
+    let cropped = workers.run(move || crop_image(&source, &source_ext, crop, &profiles, &limits, engine)).await?;
+    log::debug!("reprocessed {}/{}: {}", user_id, record.image_id, cropped.timings.server_timing());
+    let img_name = record.image_id.to_string();
+    let variants = store_variants(storage, user_id, &img_name, cropped.variants).await?;
+
+    // pending AVIF files are overwritten once they are encoded
+    let written: Vec<String> = variants
+        .iter()
+        .map(|v| variant_file_name(&img_name, &v.suffix, &v.ext))
+        .chain(cropped.pending.iter().map(|p| variant_file_name(&img_name, &p.suffix, AVIF_EXT)))
+        .collect();
+    for old in &record.variants {
+        let file_name = record.variant_file_name(old);
+        let key = format!("{}/{}", user_id, file_name);
+        if !written.contains(&file_name) && storage.exists(&key).await? {
+            storage.delete(&key).await?;
+        }
+    }
+    // until then the catalog keeps the old ones
+    let kept_avif = record.variants.drain(..).filter(|v| v.ext == AVIF_EXT && written.contains(&variant_file_name(&img_name, &v.suffix, AVIF_EXT)));
+    let variants: Vec<_> = variants.into_iter().chain(kept_avif).collect();
+    record.variants = variants;
+    catalog.put(&record).await?;
+    Ok((record, cropped.pending))
+}
+
+/// Variant files in `user_id`'s directory, by image. Sources and
+/// temporary files are left out.
+async fn stored_images(storage: &dyn Storage, user_id: i32) -> Result<BTreeMap<Uuid, Vec<ImageName>>, Error> {
+    let prefix = format!("{}/", user_id);
+    let mut images: BTreeMap<Uuid, Vec<ImageName>> = BTreeMap::new();
+    for key in storage.list(&prefix).await? {
+        if let Ok(name) = ImageName::parse(&key[prefix.len()..]) {
+            images.entry(name.id).or_default().push(name);
+        }
+    }
+    Ok(images)
+}
+
+/// A record for an image stored before there was a catalog, made from
+/// its variant `files`, so `reprocess` can rebuild it from the largest.
+async fn unrecorded(storage: &dyn Storage, user_id: i32, image_id: Uuid, files: &[ImageName]) -> Result<ImageRecord, Error> {
+    let mut variants = vec![];
+    for file in files {
+        let data = storage.get(&format!("{}/{}", user_id, file)).await?;
+        let (width, height) = image::io::Reader::new(Cursor::new(&data))
+            .with_guessed_format()?
+            .into_dimensions()?;
+        variants.push(VariantRecord {
+            name: String::new(),
+            suffix: file.suffix.clone(),
+            ext: file.ext.to_owned(),
+            density: 1,
+            width,
+            height,
+            bytes: data.len() as u64,
+            hash: hex::encode(Sha256::digest(&data)),
+        });
+    }
+    Ok(ImageRecord {
+        user_id,
+        image_id,
+        ext: files.first().map(|f| f.ext.to_owned()).unwrap_or_default(),
+        variants,
+        content_hash: String::new(),
+        original_filename: String::new(),
+        status: ImageStatus::Active,
+        crop: None,
+    })
+}
+
+/// `POST /reprocess/{userId}/{imgName}`: regenerates one image's
+/// variants. Admin only.
+pub async fn reprocess_image(req: HttpRequest, config: web::Data<Config>, workers: web::Data<WorkerPool>, avif: web::Data<AvifEncoder>, storage: web::Data<dyn Storage>, catalog: web::Data<dyn ImageCatalog>, path: web::Path<(String, String)>) -> Result<HttpResponse, Error> {
+    let user_id = UserId::parse(&path.0)?;
+    let id = image_id(&path.1)?;
+    req.user_info()?.admin(user_id.get(), "reprocess_image")?;
+    let not_found = || Error::new(ErrorKind::NotFound, "image not found.");
+    let record = match catalog.get(user_id.get(), id).await? {
+        Some(record) if record.status == ImageStatus::Active => record,
+        Some(_) => return Err(not_found()),
+        None => {
+            let files = stored_images(storage.as_ref(), user_id.get()).await?.remove(&id).ok_or_else(not_found)?;
+            unrecorded(storage.as_ref(), user_id.get(), id, &files).await?
+        }
+    };
+    let (record, pending) = reprocess(storage.as_ref(), catalog.as_ref(), &workers, &config, record).await?;
+    avif.spawn(storage, catalog, record.user_id, record.image_id, pending);
+    Ok(HttpResponse::Ok().json(UploadResponse::new(&record)))
+}
+
+/// `POST /reprocess/{userId}`: regenerates the variants of all of the
+/// user's active images, and of those stored before there was a catalog,
+/// which get a record. Admin only.
+pub async fn reprocess_user(req: HttpRequest, config: web::Data<Config>, workers: web::Data<WorkerPool>, avif: web::Data<AvifEncoder>, storage: web::Data<dyn Storage>, catalog: web::Data<dyn ImageCatalog>, path: web::Path<String>) -> Result<HttpResponse, Error> {
+    let user_id = UserId::parse(&path)?;
+    req.user_info()?.admin(user_id.get(), "reprocess_user")?;
+    let mut reprocessed = 0;
+    let mut before = None;
+    loop {
+        let page = catalog.list(user_id.get(), before, PAGE_SIZE).await?;
+        let exhausted = page.len() < PAGE_SIZE;
+        for record in page {
+            before = Some(record.image_id);
+            if record.status == ImageStatus::Active {
+                let (record, pending) = reprocess(storage.as_ref(), catalog.as_ref(), &workers, &config, record).await?;
+                avif.spawn(storage.clone(), catalog.clone(), record.user_id, record.image_id, pending);
+                reprocessed += 1;
+            }
+        }
+        if exhausted {
+            break;
+        }
+    }
+    // the ones just reprocessed have a record by now, so are skipped
+    for (id, files) in stored_images(storage.as_ref(), user_id.get()).await? {
+        if catalog.get(user_id.get(), id).await?.is_some() {
+            continue;
+        }
+        let record = unrecorded(storage.as_ref(), user_id.get(), id, &files).await?;
+        let (record, pending) = reprocess(storage.as_ref(), catalog.as_ref(), &workers, &config, record).await?;
+        avif.spawn(storage.clone(), catalog.clone(), record.user_id, record.image_id, pending);
+        reprocessed += 1;
+    }
+    Ok(HttpResponse::Ok().json(ReprocessResponse { reprocessed }))
+}
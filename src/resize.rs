@@ -0,0 +1,344 @@
+//! Convolution resizer for 8-bit RGBA images, the `fast` resize engine.
+//!
+//! Samples like `image::imageops::resize`, with the same kernels, support
+//! and pixel centres, so both engines give the same picture for a
+//! filter. The difference is in how: the weights are worked out once
+//! per image instead of per pixel, held as 14-bit fixed point, and
+//! applied in plain integer loops the compiler can vectorize; on x86-64
+//! they are built a second time for AVX2, used when the CPU has it.
+//! Rounding to fixed point means results may differ from `imageops` by
+//! a level now and then.
+//!
+//! This file only depends on `image`; `examples/resize_bench.rs`
+//! includes it to compare both engines.
+
+use std::f32::consts::PI;
+use image::RgbaImage;
+use image::imageops::FilterType;
+
+/// Fractional bits of the fixed-point weights.
+const PRECISION: u32 = 14;
+const ONE: i32 = 1 << PRECISION;
+/// Fractional bits of the 16-bit image between the two passes. Leaves
+/// room for the overshoot of sharper filters, which is only clamped at
+/// the end, as `imageops` does.
+const EXTRA: u32 = 6;
+
+fn sinc(t: f32) -> f32 {
+    if t == 0.0 {
+        1.0
+    } else {
+        let a = t * PI;
+        a.sin() / a
+    }
+}
+
+fn lanczos3(x: f32) -> f32 {
+    if x.abs() < 3.0 {
+        sinc(x) * sinc(x / 3.0)
+    } else {
+        0.0
+    }
+}
+
+/// Gaussian with a standard deviation of 0.5.
+fn gaussian(x: f32) -> f32 {
+    let r = 0.5_f32;
+    ((2.0 * PI).sqrt() * r).recip() * (-x.powi(2) / (2.0 * r.powi(2))).exp()
+}
+
+/// Mitchell-Netravali cubic with B = 0 and C = 0.5.
+fn catmull_rom(x: f32) -> f32 {
+    let a = x.abs();
+    let k = if a < 1.0 {
+        9.0 * a.powi(3) - 15.0 * a.powi(2) + 6.0
+    } else if a < 2.0 {
+        -3.0 * a.powi(3) + 15.0 * a.powi(2) - 24.0 * a + 12.0
+    } else {
+        0.0
+    };
+    k / 6.0
+}
+
+fn triangle(x: f32) -> f32 {
+    if x.abs() < 1.0 {
+        1.0 - x.abs()
+    } else {
+        0.0
+    }
+}
+
+fn nearest(_x: f32) -> f32 {
+    1.0
+}
+
+/// Kernel and support, in source pixels at a ratio of 1, of `filter`.
+fn kernel(filter: FilterType) -> (fn(f32) -> f32, f32) {
+    match filter {
+        FilterType::Nearest => (nearest, 0.0),
+        FilterType::Triangle => (triangle, 1.0),
+        FilterType::CatmullRom => (catmull_rom, 2.0),
+        FilterType::Gaussian => (gaussian, 3.0),
+        FilterType::Lanczos3 => (lanczos3, 3.0),
+    }
+}
+
+/// Fixed-point weights of every output pixel along one axis: output
+/// pixel `i` is made from source pixels `starts[i]..` with the `taps`
+/// coefficients at `coeffs[i * taps..]`. Shorter spans are padded with
+/// zeros, so every pixel takes the same number of steps.
+struct Weights {
+    starts: Vec<usize>,
+    coeffs: Vec<i32>,
+    taps: usize,
+}
+
+impl Weights {
+    fn new(src_len: u32, dst_len: u32, filter: FilterType) -> Self {
+        let (kernel, support) = kernel(filter);
+        let ratio = src_len as f32 / dst_len as f32;
+        let scale = ratio.max(1.0);
+        let src_support = support * scale;
+
+        let mut spans = Vec::with_capacity(dst_len as usize);
+        for out in 0..dst_len {
+            // same bounds and centre as `imageops`
+            let centre = (out as f32 + 0.5) * ratio;
+            let left = ((centre - src_support).floor() as i64).clamp(0, i64::from(src_len) - 1);
+            let right = ((centre + src_support).ceil() as i64).clamp(left + 1, i64::from(src_len));
+            let centre = centre - 0.5;
+            let ws: Vec<f32> = (left..right).map(|i| kernel((i as f32 - centre) / scale)).collect();
+            let sum: f32 = ws.iter().sum();
+            let mut fixed: Vec<i32> = ws.iter().map(|w| (w / sum * ONE as f32).round() as i32).collect();
+            // rounding must not change the sum, or flat areas would shift
+            let drift = ONE - fixed.iter().sum::<i32>();
+            if let Some(largest) = (0..fixed.len()).max_by_key(|&i| fixed[i].abs()) {
+                fixed[largest] += drift;
+            }
+            spans.push((left as usize, fixed));
+        }
+
+        let taps = spans.iter().map(|(_, fixed)| fixed.len()).max().unwrap_or_default();
+        let mut starts = Vec::with_capacity(spans.len());
+        let mut coeffs = vec![0; spans.len() * taps];
+        for (i, (start, fixed)) in spans.into_iter().enumerate() {
+            // a span near the end starts further left, its weights
+            // further right, so all `taps` pixels are in the source
+            let padded = start.min(src_len as usize - taps);
+            let offset = i * taps + start - padded;
+            starts.push(padded);
+            coeffs[offset..offset + fixed.len()].copy_from_slice(&fixed);
+        }
+        Weights { starts, coeffs, taps }
+    }
+}
+
+/// Shifts a weighted sum back down by `shift` bits, rounding to nearest.
+fn round_shift(acc: i32, shift: u32) -> i32 {
+    (acc + (1 << (shift - 1))) >> shift
+}
+
+/// Runs `$pass` compiled for AVX2 when the CPU has it, and for the
+/// baseline target otherwise. The loops are the same; AVX2 only lets the
+/// compiler vectorize them twice as wide and multiply 32-bit lanes
+/// natively.
+macro_rules! dispatch {
+    ($pass:ident, $avx2:ident, ($($arg:ident: $ty:ty),*) -> $out:ty) => {
+        #[cfg(target_arch = "x86_64")]
+        #[target_feature(enable = "avx2")]
+        unsafe fn $avx2($($arg: $ty),*) -> $out {
+            $pass($($arg),*)
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        if is_x86_feature_detected!("avx2") {
+            // SAFETY: the CPU was just found to support AVX2
+            return unsafe { $avx2($($arg),*) };
+        }
+        return $pass($($arg),*);
+    };
+}
+
+fn vertical(src: &[u8], row_len: usize, weights: &Weights) -> Vec<i16> {
+    dispatch!(vertical_pass, vertical_avx2, (src: &[u8], row_len: usize, weights: &Weights) -> Vec<i16>);
+}
+
+fn horizontal(src: &[i16], width: usize, weights: &Weights) -> Vec<u8> {
+    dispatch!(horizontal_pass, horizontal_avx2, (src: &[i16], width: usize, weights: &Weights) -> Vec<u8>);
+}
+
+/// Scales `src`, rows of `row_len` bytes, to `weights.starts.len()` rows.
+/// Each output row is a weighted sum of whole source rows, kept with
+/// `EXTRA` fractional bits and without clamping for the second pass.
+#[inline(always)]
+fn vertical_pass(src: &[u8], row_len: usize, weights: &Weights) -> Vec<i16> {
+    let mut dst = vec![0; weights.starts.len() * row_len];
+    let mut acc = vec![0i32; row_len];
+    for ((dst_row, &start), coeffs) in dst
+        .chunks_exact_mut(row_len)
+        .zip(&weights.starts)
+        .zip(weights.coeffs.chunks_exact(weights.taps))
+    {
+        acc.fill(0);
+        let span = &src[start * row_len..(start + weights.taps) * row_len];
+        for (&c, src_row) in coeffs.iter().zip(span.chunks_exact(row_len)) {
+            if c == 0 {
+                continue;
+            }
+            for (a, &v) in acc.iter_mut().zip(src_row) {
+                *a += c * i32::from(v);
+            }
+        }
+        for (out, &a) in dst_row.iter_mut().zip(&acc) {
+            *out = round_shift(a, PRECISION - EXTRA).clamp(i32::from(i16::MIN), i32::from(i16::MAX)) as i16;
+        }
+    }
+    dst
+}
+
+/// Scales the rows of `src`, `width` pixels each, to `weights.starts.len()`
+/// pixels and back to 8 bits.
+#[inline(always)]
+fn horizontal_pass(src: &[i16], width: usize, weights: &Weights) -> Vec<u8> {
+    let dst_width = weights.starts.len();
+    let rows = src.len() / (width * 4);
+    let mut dst = vec![0; dst_width * rows * 4];
+    for (src_row, dst_row) in src.chunks_exact(width * 4).zip(dst.chunks_exact_mut(dst_width * 4)) {
+        for ((pixel, &start), coeffs) in dst_row
+            .chunks_exact_mut(4)
+            .zip(&weights.starts)
+            .zip(weights.coeffs.chunks_exact(weights.taps))
+        {
+            let mut acc = [0i32; 4];
+            let span = &src_row[start * 4..(start + weights.taps) * 4];
+            for (&c, p) in coeffs.iter().zip(span.chunks_exact(4)) {
+                acc[0] += c * i32::from(p[0]);
+                acc[1] += c * i32::from(p[1]);
+                acc[2] += c * i32::from(p[2]);
+                acc[3] += c * i32::from(p[3]);
+            }
+            for (out, acc) in pixel.iter_mut().zip(acc) {
+                *out = round_shift(acc, PRECISION + EXTRA).clamp(0, 255) as u8;
+            }
+        }
+    }
+    dst
+}
+
+/// Scales `src` to `width` x `height` with `filter`, like
+/// `imageops::resize` does.
+pub fn resize(src: &RgbaImage, width: u32, height: u32, filter: FilterType) -> RgbaImage {
+    let (src_width, src_height) = src.dimensions();
+    if (width, height) == (src_width, src_height) {
+        return src.clone();
+    }
+    if width == 0 || height == 0 || src_width == 0 || src_height == 0 {
+        return RgbaImage::new(width, height);
+    }
+    resize_with(src, width, height, filter, vertical, horizontal)
+}
+
+type VerticalPass = fn(&[u8], usize, &Weights) -> Vec<i16>;
+type HorizontalPass = fn(&[i16], usize, &Weights) -> Vec<u8>;
+
+/// `resize` with the given passes, so tests can run the baseline ones
+/// on a CPU that has AVX2.
+fn resize_with(src: &RgbaImage, width: u32, height: u32, filter: FilterType, vertical: VerticalPass, horizontal: HorizontalPass) -> RgbaImage {
+    let (src_width, src_height) = src.dimensions();
+    // vertical first, like `imageops`: every source byte is then read
+    // in whole rows, and only the shorter image is scaled across. Both
+    // passes run even for an axis that keeps its size; the gaussian
+    // blurs it.
+    let rows = vertical(src.as_raw(), src_width as usize * 4, &Weights::new(src_height, height, filter));
+    let data = horizontal(&rows, src_width as usize, &Weights::new(src_width, width, filter));
+    RgbaImage::from_raw(width, height, data).expect("buffer matches the output size")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::imageops;
+
+    const FILTERS: [FilterType; 5] = [
+        FilterType::Nearest,
+        FilterType::Triangle,
+        FilterType::CatmullRom,
+        FilterType::Gaussian,
+        FilterType::Lanczos3,
+    ];
+
+    /// Source and output sizes: down, up, single pixel rows and columns,
+    /// a single pixel either way, and one axis only.
+    const CASES: [((u32, u32), (u32, u32)); 10] = [
+        ((97, 61), (31, 20)),
+        ((31, 20), (97, 61)),
+        ((1, 53), (1, 17)),
+        ((53, 1), (17, 1)),
+        ((1, 17), (1, 53)),
+        ((5, 4), (1, 1)),
+        ((1, 1), (6, 5)),
+        ((64, 48), (64, 19)),
+        ((64, 48), (23, 48)),
+        ((40, 30), (61, 11)),
+    ];
+
+    /// Noise, with sharp edges that make the kernels overshoot.
+    fn noise(width: u32, height: u32) -> RgbaImage {
+        let mut state = 0x2545_f491_u32 ^ (width << 16) ^ height;
+        RgbaImage::from_fn(width, height, |x, _| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let [r, g, b, a] = state.to_le_bytes();
+            if x % 7 == 0 {
+                image::Rgba([255, 0, 255, 255])
+            } else {
+                image::Rgba([r, g, b, a])
+            }
+        })
+    }
+
+    fn max_diff(a: &RgbaImage, b: &RgbaImage) -> u8 {
+        a.as_raw().iter().zip(b.as_raw()).map(|(x, y)| x.abs_diff(*y)).max().unwrap_or_default()
+    }
+
+    fn check(vertical: VerticalPass, horizontal: HorizontalPass) {
+        for filter in FILTERS {
+            for ((src_width, src_height), (width, height)) in CASES {
+                let src = noise(src_width, src_height);
+                let expected = imageops::resize(&src, width, height, filter);
+                let actual = resize_with(&src, width, height, filter, vertical, horizontal);
+                assert_eq!(actual.dimensions(), (width, height));
+                let diff = max_diff(&expected, &actual);
+                assert!(
+                    diff <= 1,
+                    "{:?} {}x{} -> {}x{} differs by {}",
+                    filter,
+                    src_width,
+                    src_height,
+                    width,
+                    height,
+                    diff
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn baseline_matches_imageops() {
+        check(vertical_pass, horizontal_pass);
+    }
+
+    /// Takes the AVX2 passes where the CPU has them, like `resize`.
+    #[test]
+    fn dispatched_matches_imageops() {
+        check(vertical, horizontal);
+    }
+
+    #[test]
+    fn keeps_the_size() {
+        let src = noise(13, 7);
+        assert_eq!(resize(&src, 13, 7, FilterType::Lanczos3), src);
+        assert_eq!(resize(&src, 0, 7, FilterType::Lanczos3).dimensions(), (0, 7));
+    }
+}
@@ -2,44 +2,58 @@ use crate::upload::{upload_image, update_image};
 use crate::postman;
 use crate::unique::time_uuid;
 use crate::delete_image::delete_image;
-use crate::{PATH};
+use crate::reprocess::{reprocess_image, reprocess_user};
+use crate::list_images::list_images;
+use crate::serve::{download_name, serve_object, ServeOptions, ServeQuery};
+use crate::catalog::ImageCatalog;
+use crate::config::Config;
+use crate::negotiate::serve_variant;
+use crate::storage::Storage;
+use crate::auth::AuthSession;
+use crate::safe_path::{ImageName, UserId};
 
-use std::fs;
-use actix_files;
-use std::path::PathBuf;
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use crate::middleware::Authentication;
 
-use crate::error::Error;
-use actix_web::http::StatusCode;
+use crate::error::{Error, ErrorKind};
 use actix_web::{web, HttpRequest, HttpResponse};
-use actix_web::http::header::{ContentDisposition, DispositionType};
 use anyhow::Result;
 
-async fn index(req: HttpRequest) -> Result<actix_files::NamedFile, Error> {
-    let mut images_dir = PathBuf::from(PATH);
-    let file_name: std::path::PathBuf = req.match_info().query("filename").parse().unwrap();
-    images_dir.push(file_name);
-
-    let full_path = images_dir.to_str().map(|a| a.to_owned()).unwrap();
-    let file = actix_files::NamedFile::open(full_path)?;
-    Ok(file
-        .use_last_modified(true)
-        .set_content_disposition(ContentDisposition {
-            disposition: DispositionType::Attachment,
-            parameters: vec![],
-        }))
+/// Serves one variant file by name, with its `ETag` and original file
+/// name from the catalog when the image has a record.
+async fn serve_file(req: &HttpRequest, storage: &dyn Storage, catalog: &dyn ImageCatalog, config: &Config, query: &ServeQuery, user_id: &str, file_name: &str) -> Result<HttpResponse, Error> {
+    let user_id = UserId::parse(user_id)?;
+    let name = ImageName::parse(file_name)?;
+    let record = catalog.get(user_id.get(), name.id).await?;
+    let hash = record.as_ref().and_then(|record| {
+        record
+            .variants
+            .iter()
+            .find(|v| v.suffix == name.suffix && record.variant_ext(v) == name.ext)
+            .map(|v| v.hash.as_str())
+            .filter(|hash| !hash.is_empty())
+    });
+    let original = record.as_ref().map(|record| record.original_filename.as_str());
+    let options = ServeOptions {
+        download: query.download().then(|| download_name(original, file_name)),
+        cache_control: &config.cache.files,
+        hash,
+    };
+    serve_object(req, storage, &name.key(user_id), options).await
 }
 
-async fn get_image_by_id(path: web::Path<(String, String)>) -> Result<actix_files::NamedFile, Error> {
-    let full_path = format!("{}/{}/{}", PATH, path.0, path.1);
-    let file = actix_files::NamedFile::open(&full_path)?;
-    Ok(file
-        .use_last_modified(true)
-        .set_content_disposition(ContentDisposition {
-            disposition: DispositionType::Attachment,
-            parameters: vec![],
-        }))
+async fn index(storage: web::Data<dyn Storage>, catalog: web::Data<dyn ImageCatalog>, config: web::Data<Config>, query: web::Query<ServeQuery>, req: HttpRequest) -> Result<HttpResponse, Error> {
+    let (user_id, file_name) = req
+        .match_info()
+        .query("filename")
+        .split_once('/')
+        .ok_or_else(|| Error::new(ErrorKind::InvalidPath, "expected {userId}/{imgName}"))?;
+    serve_file(&req, storage.as_ref(), catalog.as_ref(), &config, &query, user_id, file_name).await
+}
+
+async fn get_image_by_id(storage: web::Data<dyn Storage>, catalog: web::Data<dyn ImageCatalog>, config: web::Data<Config>, query: web::Query<ServeQuery>, req: HttpRequest, path: web::Path<(String, String)>) -> Result<HttpResponse, Error> {
+    serve_file(&req, storage.as_ref(), catalog.as_ref(), &config, &query, &path.0, &path.1).await
 }
 
 #[derive(Serialize, Deserialize)]
@@ -47,34 +61,22 @@ struct UserRequest {
     user_id: String,
 }
 
-#[derive(Serialize)]
-pub struct ErrorResponse {
-    status: u16,
-    message: String,
-}
-
-async fn create_user_dir(request: web::Json<UserRequest>) -> HttpResponse {
-    let path = format!("{}{}", PATH, &request.user_id);
-    match fs::create_dir(&path) {
-        Ok(_) => {
-            HttpResponse::Ok().body("Created dir.")
-        },
-        Err(e) => {
-            HttpResponse::build(StatusCode::FORBIDDEN).json(ErrorResponse {
-                status: 403,
-                message: e.to_string()
-            })
-        }
+async fn create_user_dir(req: HttpRequest, storage: web::Data<dyn Storage>, request: web::Json<UserRequest>) -> Result<HttpResponse, Error> {
+    let user_id = UserId::parse(&request.user_id)?;
+    let user_id = req.user_info()?.owner(Some(user_id.get()), "create_user_dir")?;
+    if storage.exists(&user_id.to_string()).await? {
+        return Err(Error::new(ErrorKind::Conflict, "user dir already exists."));
     }
+    storage.put_bytes(&format!("{}/.keep", user_id), Bytes::new()).await?;
+    Ok(HttpResponse::Ok().body("Created dir."))
 }
 
 async fn home() -> HttpResponse {
     HttpResponse::Ok().body("Home!")
 }
 
-async fn new_uuid(path: web::Path<String>) -> HttpResponse {
-    let l = str::parse::<u32>(path.as_ref()).unwrap();
-    for _ in 0..l {
+async fn new_uuid(path: web::Path<u32>) -> HttpResponse {
+    for _ in 0..path.into_inner() {
         let x = time_uuid();
         let y = x.to_string();
         println!("'{}'", y);
@@ -82,18 +84,409 @@ async fn new_uuid(path: web::Path<String>) -> HttpResponse {
     HttpResponse::Ok().body("Done.")
 }
 
+/// Extractor failures answer with the same problem+json body as handler
+/// errors.
+fn extractor_error(source: &str, e: impl std::fmt::Display) -> actix_web::Error {
+    Error::new(ErrorKind::InvalidRequest, format!("invalid {}", source))
+        .with_field(source, e.to_string())
+        .into()
+}
+
 pub fn routes(config: &mut web::ServiceConfig) {
+    config.app_data(web::PathConfig::default().error_handler(|e, _| extractor_error("path", e)));
+    config.app_data(web::QueryConfig::default().error_handler(|e, _| extractor_error("query", e)));
+    config.app_data(web::JsonConfig::default().error_handler(|e, _| extractor_error("body", e)));
     config.route("/", web::get().to(home));
-    config.route("/images/{filename:.*}", web::get().to(index));
-    config.route("/getimage/{userid}/{filename}", web::get().to(get_image_by_id));
-    config.route("/create_user_dir", web::post().to(create_user_dir));
-    config.service(web::resource("/upload_image").route(web::post().to(upload_image)));
-    config.service(web::resource("/update_image").route(web::post().to(update_image)));
+    config.service(web::resource("/images/{filename:.*}").route(web::get().to(index)).route(web::head().to(index)));
+    config.service(
+        web::resource("/getimage/{userid}/{filename}")
+        .route(web::get().to(get_image_by_id))
+        .route(web::head().to(get_image_by_id))
+    );
+    config.service(
+        web::resource("/i/{userId}/{imgName}/{variant}")
+        .route(web::get().to(serve_variant))
+        .route(web::head().to(serve_variant))
+    );
+    config.service(web::resource("/users/{userId}/images").wrap(Authentication).route(web::get().to(list_images)));
+    config.service(web::resource("/create_user_dir").wrap(Authentication).route(web::post().to(create_user_dir)));
+    config.service(web::resource("/upload_image").wrap(Authentication).route(web::post().to(upload_image)));
+    config.service(web::resource("/update_image").wrap(Authentication).route(web::post().to(update_image)));
     config.service(web::resource("/test_image").route(web::post().to(postman::upload_image)));
     config.route("/new_id/{id}", web::get().to(new_uuid));
     config.service(
         web::scope("/delete")
-        .wrap(Authentication{})
+        .wrap(Authentication)
         .route("/image/{userId}/{image_name}", web::post().to(delete_image))
     );
-}
\ No newline at end of file
+    config.service(
+        web::scope("/reprocess")
+        .wrap(Authentication)
+        .route("/{userId}", web::post().to(reprocess_user))
+        .route("/{userId}/{imgName}", web::post().to(reprocess_image))
+    );
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::JwtVerifier;
+    use crate::catalog::{EmbeddedCatalog, ImageStatus};
+    use crate::storage::MemoryStorage;
+    use crate::workers::WorkerPool;
+    use crate::avif::AvifEncoder;
+
+    use std::io::Cursor;
+    use std::sync::Arc;
+    use actix_http::Request;
+    use actix_web::body::BoxBody;
+    use actix_web::dev::{Service, ServiceResponse};
+    use actix_web::{http::{header, StatusCode}, test, App};
+    use image::{DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+
+    const SECRET: &str = "route-tests";
+    const BOUNDARY: &str = "lily-test-boundary";
+
+    fn bearer(user_id: i32) -> (header::HeaderName, String) {
+        bearer_as(user_id, "user")
+    }
+
+    fn bearer_as(user_id: i32, role: &str) -> (header::HeaderName, String) {
+        #[derive(Serialize)]
+        struct Claims<'a> {
+            sub: String,
+            exp: u64,
+            role: &'a str,
+        }
+        let claims = Claims { sub: user_id.to_string(), exp: 4_102_444_800, role };
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::default(),
+            &claims,
+            &jsonwebtoken::EncodingKey::from_secret(SECRET.as_bytes()),
+        )
+        .unwrap();
+        (header::AUTHORIZATION, format!("Bearer {}", token))
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let img = RgbaImage::from_pixel(width, height, Rgba([200, 120, 40, 255]));
+        let mut out = Cursor::new(vec![]);
+        DynamicImage::ImageRgba8(img).write_to(&mut out, ImageOutputFormat::Png).unwrap();
+        out.into_inner()
+    }
+
+    /// An upload body: the metadata part, then the image part.
+    fn upload_body(metadata: &str, file_name: &str, image: &[u8]) -> Vec<u8> {
+        let mut body = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"metadata\"\r\nContent-Type: application/json\r\n\r\n{m}\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"image\"; filename=\"{f}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            b = BOUNDARY,
+            m = metadata,
+            f = file_name,
+        )
+        .into_bytes();
+        body.extend_from_slice(image);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", BOUNDARY).as_bytes());
+        body
+    }
+
+    /// Storage and catalog of one test, alive as long as it is.
+    struct Stores {
+        storage: web::Data<dyn Storage>,
+        catalog: Arc<dyn ImageCatalog>,
+        _dir: tempfile::TempDir,
+    }
+
+    fn stores() -> Stores {
+        let dir = tempfile::tempdir().unwrap();
+        Stores {
+            storage: web::Data::from(Arc::new(MemoryStorage::default()) as Arc<dyn Storage>),
+            catalog: Arc::new(EmbeddedCatalog::open(&dir.path().join("catalog")).unwrap()),
+            _dir: dir,
+        }
+    }
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.auth.jwt.hs256_secret = Some(SECRET.to_owned());
+        config.workers.threads = 1;
+        config
+    }
+
+    async fn service(config: Config, stores: &Stores) -> impl Service<Request, Response = ServiceResponse<BoxBody>, Error = actix_web::Error> {
+        test::init_service(
+            App::new()
+                .app_data(web::Data::new(JwtVerifier::from_config(&config.auth.jwt).unwrap()))
+                .app_data(web::Data::new(WorkerPool::new(&config.workers).unwrap()))
+                .app_data(web::Data::new(AvifEncoder::new(&config.avif).unwrap()))
+                .app_data(stores.storage.clone())
+                .app_data(web::Data::from(stores.catalog.clone()))
+                .app_data(web::Data::new(config))
+                .configure(routes),
+        )
+        .await
+    }
+
+    /// A multipart POST of `metadata` and `image` to `uri` as `user_id`.
+    fn post_image(uri: &str, user_id: i32, metadata: &str, file_name: &str, image: &[u8]) -> Request {
+        test::TestRequest::post()
+            .uri(uri)
+            .insert_header(bearer(user_id))
+            .insert_header((header::CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY)))
+            .set_payload(upload_body(metadata, file_name, image))
+            .to_request()
+    }
+
+    #[actix_web::test]
+    async fn upload_serve_delete() {
+        let stores = stores();
+        let storage = stores.storage.clone();
+        let app = service(config(), &stores).await;
+
+        let metadata = r#"{"xAxis":0,"yAxis":0,"imgWidth":64,"imgHeight":48}"#;
+        let req = post_image("/upload_image", 12, metadata, "photo.png", &png(64, 48));
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let uploaded: serde_json::Value = test::read_body_json(res).await;
+        let img_name = uploaded["imgName"].as_str().unwrap().to_owned();
+        assert_eq!(uploaded["imgExt"], "png");
+        // the crop is smaller than every profile, so no variant is scaled
+        assert_eq!(uploaded["variants"].as_array().unwrap().len(), 3);
+        assert!(storage.exists(&format!("12/{}.png", img_name)).await.unwrap());
+
+        let file = format!("/getimage/12/{}_320.png", img_name);
+        let res = test::call_service(&app, test::TestRequest::get().uri(&file).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get(header::CONTENT_TYPE).unwrap(), "image/png");
+        // reprocessing rewrites files in place, so they must be revalidated
+        let cache_control = res.headers().get(header::CACHE_CONTROL).unwrap().to_str().unwrap();
+        assert!(!cache_control.contains("immutable"), "{}", cache_control);
+        let body = test::read_body(res).await;
+        assert_eq!(image::load_from_memory(&body).unwrap().to_rgba8().dimensions(), (64, 48));
+
+        // only the owner may list it
+        let req = test::TestRequest::get().uri("/users/12/images").insert_header(bearer(12)).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let listed: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(listed["images"][0]["imgName"], img_name.as_str());
+        assert_eq!(listed["images"][0]["originalName"], "photo.png");
+        let req = test::TestRequest::get().uri("/users/12/images").insert_header(bearer(13)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::get().uri("/users/12/images").insert_header((header::AUTHORIZATION, "Bearer nope")).to_request();
+        let e = test::try_call_service(&app, req).await.unwrap_err();
+        assert_eq!(e.as_response_error().status_code(), StatusCode::UNAUTHORIZED);
+
+        // someone else may not delete it
+        let delete = format!("/delete/image/12/{}", img_name);
+        let req = test::TestRequest::post().uri(&delete).insert_header(bearer(13)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post().uri(&delete).insert_header(bearer(12)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let res = test::call_service(&app, test::TestRequest::get().uri(&file).to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(storage.list("12/").await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn update_replaces_only_once_the_old_image_is_trashed() {
+        let stores = stores();
+        let storage = stores.storage.clone();
+        let app = service(config(), &stores).await;
+
+        let metadata = r#"{"xAxis":0,"yAxis":0,"imgWidth":64,"imgHeight":48}"#;
+        let res = test::call_service(&app, post_image("/upload_image", 12, metadata, "old.png", &png(64, 48))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let old: serde_json::Value = test::read_body_json(res).await;
+        let old_name = old["imgName"].as_str().unwrap().to_owned();
+
+        let update = format!(r#"{{"xAxis":0,"yAxis":0,"imgWidth":40,"imgHeight":30,"imgExt":"png","imgName":"{}"}}"#, old_name);
+        let res = test::call_service(&app, post_image("/update_image", 12, &update, "new.png", &png(40, 30))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let new: serde_json::Value = test::read_body_json(res).await;
+        let new_name = new["imgName"].as_str().unwrap().to_owned();
+        assert!(storage.exists(&format!(".trash/{}_320.png", old_name)).await.unwrap());
+        let mut keys = storage.list("12/").await.unwrap();
+        keys.sort();
+        assert!(keys.iter().all(|key| key.contains(&new_name)), "{:?}", keys);
+        let old_record = stores.catalog.get(12, old_name.parse().unwrap()).await.unwrap().unwrap();
+        assert_eq!(old_record.status, ImageStatus::Trashed);
+
+        // replacing it again finds nothing to replace and stores nothing
+        let res = test::call_service(&app, post_image("/update_image", 12, &update, "again.png", &png(40, 30))).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let mut after = storage.list("12/").await.unwrap();
+        after.sort();
+        assert_eq!(after, keys);
+        assert_eq!(stores.catalog.list(12, None, 10).await.unwrap().len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn update_trashes_a_legacy_image() {
+        let stores = stores();
+        let storage = stores.storage.clone();
+        let app = service(config(), &stores).await;
+
+        // stored before the catalog, so only these two files and no record
+        let legacy = time_uuid().to_string();
+        for suffix in ["720", "320"] {
+            storage.put_bytes(&format!("12/{}_{}.png", legacy, suffix), png(8, 6).into()).await.unwrap();
+        }
+        let update = format!(r#"{{"xAxis":0,"yAxis":0,"imgWidth":40,"imgHeight":30,"imgExt":"png","imgName":"{}"}}"#, legacy);
+        let res = test::call_service(&app, post_image("/update_image", 12, &update, "new.png", &png(40, 30))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let new: serde_json::Value = test::read_body_json(res).await;
+        let new_name = new["imgName"].as_str().unwrap();
+        for suffix in ["720", "320"] {
+            assert!(storage.exists(&format!(".trash/{}_{}.png", legacy, suffix)).await.unwrap());
+        }
+        let keys = storage.list("12/").await.unwrap();
+        assert!(keys.iter().all(|key| key.contains(new_name)), "{:?}", keys);
+        assert!(stores.catalog.get(12, new_name.parse().unwrap()).await.unwrap().is_some());
+
+        // an id with neither a record nor legacy files is a 404
+        let unknown = format!(r#"{{"xAxis":0,"yAxis":0,"imgWidth":40,"imgHeight":30,"imgExt":"png","imgName":"{}"}}"#, time_uuid());
+        let res = test::call_service(&app, post_image("/update_image", 12, &unknown, "new.png", &png(40, 30))).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(storage.list("12/").await.unwrap().len(), keys.len());
+        assert_eq!(stores.catalog.list(12, None, 10).await.unwrap().len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn reprocess_rebuilds_unrecorded_images() {
+        let stores = stores();
+        let storage = stores.storage.clone();
+        let app = service(config(), &stores).await;
+
+        let legacy = [time_uuid(), time_uuid()];
+        for id in legacy {
+            storage.put_bytes(&format!("12/{}_720.png", id), png(72, 54).into()).await.unwrap();
+            storage.put_bytes(&format!("12/{}_320.png", id), png(32, 24).into()).await.unwrap();
+        }
+
+        let req = test::TestRequest::post().uri(&format!("/reprocess/12/{}", legacy[0])).insert_header(bearer_as(1, "admin")).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+        let req = test::TestRequest::post().uri("/reprocess/12").insert_header(bearer_as(1, "admin")).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        // the first one has a record by now and is counted once
+        assert_eq!(body["reprocessed"], 2);
+
+        for id in legacy {
+            let record = stores.catalog.get(12, id).await.unwrap().unwrap();
+            let mut suffixes: Vec<_> = record.variants.iter().map(|v| v.suffix.as_str()).collect();
+            suffixes.sort();
+            assert_eq!(suffixes, ["1920", "320", "720"]);
+            // rebuilt from the largest, which is smaller than every profile
+            assert!(record.variants.iter().all(|v| (v.width, v.height) == (72, 54)));
+        }
+        let keys = storage.list("12/").await.unwrap();
+        assert_eq!(keys.len(), 6, "{:?}", keys);
+        assert!(keys.iter().all(|key| !key.ends_with(".tmp")));
+    }
+
+    #[actix_web::test]
+    async fn upload_refuses_misnamed_and_unsupported_images() {
+        let stores = stores();
+        let storage = stores.storage.clone();
+        let app = service(config(), &stores).await;
+
+        let metadata = r#"{"xAxis":0,"yAxis":0,"imgWidth":64,"imgHeight":48}"#;
+        let gif = b"GIF89a\x40\x00\x30\x00\x00\x00\x00;".as_slice();
+        for (file_name, image, code) in [
+            ("photo.jpg", png(64, 48), "CONTENT_MISMATCH"),
+            ("anim.gif", gif.to_vec(), "UNSUPPORTED_IMAGE"),
+            ("photo.png", b"garbage".to_vec(), "UNSUPPORTED_IMAGE"),
+        ] {
+            let res = test::call_service(&app, post_image("/upload_image", 12, metadata, file_name, &image)).await;
+            assert_eq!(res.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE, "{}", file_name);
+            let problem: serde_json::Value = test::read_body_json(res).await;
+            assert_eq!(problem["detail"], code);
+        }
+        // the uploaded bytes are not kept
+        assert!(storage.list("12/").await.unwrap().is_empty());
+
+        // the extension may be in any case, after other dots
+        let res = test::call_service(&app, post_image("/upload_image", 12, metadata, "my.photo.PNG", &png(64, 48))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    /// Status and problem `errors`, by field, of an upload of `image` as
+    /// user 12 under `limits`, and what was left in storage.
+    async fn upload_limited(limits: crate::config::LimitsConfig, metadata: &str, image: &[u8]) -> (StatusCode, serde_json::Map<String, serde_json::Value>, Vec<String>) {
+        let stores = stores();
+        let mut config = config();
+        config.limits = limits;
+        let app = service(config, &stores).await;
+        let res = test::call_service(&app, post_image("/upload_image", 12, metadata, "photo.png", image)).await;
+        let status = res.status();
+        let problem: serde_json::Value = test::read_body_json(res).await;
+        let errors = problem["errors"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|e| (e["field"].as_str().unwrap().to_owned(), e["message"].clone()))
+            .collect();
+        (status, errors, stores.storage.list("12/").await.unwrap())
+    }
+
+    #[actix_web::test]
+    async fn oversized_uploads_are_413() {
+        let metadata = r#"{"xAxis":0,"yAxis":0,"imgWidth":64,"imgHeight":48}"#;
+        let image = png(64, 48);
+        let defaults = crate::config::LimitsConfig::default();
+
+        let limits = crate::config::LimitsConfig { max_request_bytes: image.len() as u64, ..defaults };
+        let (status, errors, left) = upload_limited(limits, metadata, &image).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(errors.contains_key("request"), "{:?}", errors);
+        assert!(left.is_empty(), "{:?}", left);
+
+        let limits = crate::config::LimitsConfig { max_image_bytes: image.len() as u64 - 1, ..defaults };
+        let (status, errors, left) = upload_limited(limits, metadata, &image).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(errors.contains_key("image"), "{:?}", errors);
+        assert!(left.is_empty(), "{:?}", left);
+
+        let limits = crate::config::LimitsConfig { max_metadata_bytes: metadata.len() as u64 - 1, ..defaults };
+        let (status, errors, left) = upload_limited(limits, metadata, &image).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(errors.contains_key("metadata"), "{:?}", errors);
+        assert!(left.is_empty(), "{:?}", left);
+
+        // exactly at every limit is fine
+        let limits = crate::config::LimitsConfig { max_image_bytes: image.len() as u64, max_metadata_bytes: metadata.len() as u64, ..defaults };
+        let (status, _, left) = upload_limited(limits, metadata, &image).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(!left.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn oversized_images_are_422() {
+        let metadata = r#"{"xAxis":0,"yAxis":0,"imgWidth":10,"imgHeight":10}"#;
+        let defaults = crate::config::LimitsConfig::default();
+
+        let limits = crate::config::LimitsConfig { max_width: 100, ..defaults };
+        let (status, errors, left) = upload_limited(limits, metadata, &png(101, 10)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(errors["image"], "exceeds 100x16384 pixels");
+        assert!(left.is_empty(), "{:?}", left);
+
+        let limits = crate::config::LimitsConfig { max_height: 100, ..defaults };
+        let (status, _, left) = upload_limited(limits, metadata, &png(10, 101)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(left.is_empty(), "{:?}", left);
+
+        let limits = crate::config::LimitsConfig { max_pixels: 1599, ..defaults };
+        let (status, errors, left) = upload_limited(limits, metadata, &png(40, 40)).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(errors["image"], "is 40x40, more than 1599 pixels");
+        assert!(left.is_empty(), "{:?}", left);
+
+        let limits = crate::config::LimitsConfig { max_pixels: 1600, ..defaults };
+        let (status, _, _) = upload_limited(limits, metadata, &png(40, 40)).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+}
+
@@ -0,0 +1,283 @@
+use crate::error::{Error, ErrorKind};
+
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Extensions accepted for uploads and image names.
+pub static IMAGE_EXTS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
+
+/// Extensions variant files may have: the upload formats and AVIF.
+pub static VARIANT_EXTS: [&str; 5] = ["jpg", "jpeg", "png", "webp", "avif"];
+
+fn invalid(field: &str, message: &str) -> Error {
+    Error::new(ErrorKind::InvalidPath, format!("invalid {}", field)).with_field(field, message)
+}
+
+/// A user id taken from request input: plain decimal digits without
+/// sign, padding or leading zeros, so it maps to exactly one directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UserId(i32);
+
+impl UserId {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let canonical = !s.is_empty()
+            && s.bytes().all(|b| b.is_ascii_digit())
+            && (s == "0" || !s.starts_with('0'));
+        if !canonical {
+            return Err(invalid("userId", "must be a plain decimal number"));
+        }
+        s.parse().map(UserId).map_err(|_| invalid("userId", "is out of range"))
+    }
+
+    pub fn get(self) -> i32 {
+        self.0
+    }
+}
+
+impl fmt::Display for UserId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Extension of an uploaded file; anything off the list is a 415.
+pub fn image_ext(s: &str) -> Result<&str, Error> {
+    if IMAGE_EXTS.contains(&s) {
+        Ok(s)
+    } else {
+        Err(Error::new(ErrorKind::UnsupportedMediaType, "INVALID_EXT")
+            .with_field("ext", format!("must be one of {}", IMAGE_EXTS.join(", "))))
+    }
+}
+
+/// Parses the timeuuid that names an image. Only the lowercase hyphenated
+/// form of a v1 UUID is accepted, the form `time_uuid()` hands out.
+pub fn image_id(s: &str) -> Result<Uuid, Error> {
+    let id = Uuid::parse_str(s).map_err(|_| invalid("imgName", "must be a timeuuid"))?;
+    if id.get_version_num() != 1 || id.to_hyphenated().to_string() != s {
+        return Err(invalid("imgName", "must be a lowercase hyphenated v1 UUID"));
+    }
+    Ok(id)
+}
+
+/// Whether `s` may be used as a variant suffix: ASCII letters and
+/// digits only. Config validation applies the same rule to profiles.
+pub fn is_variant_suffix(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_alphanumeric())
+}
+
+/// A profile suffix, optionally followed by a pixel density like `@2x`.
+fn is_stored_suffix(s: &str) -> bool {
+    match s.split_once('@') {
+        Some((suffix, density)) => {
+            let density = density.strip_suffix('x').unwrap_or_default();
+            is_variant_suffix(suffix)
+                && !density.is_empty()
+                && !density.starts_with('0')
+                && density.bytes().all(|b| b.is_ascii_digit())
+        }
+        None => is_variant_suffix(s),
+    }
+}
+
+/// A variant file name, `{timeuuid}_{suffix}.{ext}`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageName {
+    pub id: Uuid,
+    pub suffix: String,
+    pub ext: &'static str,
+}
+
+impl ImageName {
+    pub fn parse(s: &str) -> Result<Self, Error> {
+        let shape = || invalid("imgName", "must be {timeuuid}_{suffix}.{ext}");
+        let (stem, ext) = s.rsplit_once('.').ok_or_else(shape)?;
+        let (id, suffix) = stem.rsplit_once('_').ok_or_else(shape)?;
+        if !is_stored_suffix(suffix) {
+            return Err(invalid("imgName", "has an invalid variant suffix"));
+        }
+        Ok(ImageName {
+            id: image_id(id)?,
+            suffix: suffix.to_owned(),
+            ext: VARIANT_EXTS
+                .iter()
+                .find(|known| **known == ext)
+                .ok_or_else(|| invalid("imgName", "has an unknown extension"))?,
+        })
+    }
+
+    /// Storage key of this file in `user`'s directory.
+    pub fn key(&self, user: UserId) -> String {
+        format!("{}/{}", user, self)
+    }
+}
+
+impl fmt::Display for ImageName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}_{}.{}", self.id.to_hyphenated(), self.suffix, self.ext)
+    }
+}
+
+/// Last line of defence in the storage backends: a key is a relative,
+/// `/`-separated path without empty, `.` or `..` segments, backslashes
+/// or NUL bytes.
+pub fn check_key(key: &str) -> Result<(), Error> {
+    let valid = !key.is_empty()
+        && !key.contains('\0')
+        && !key.contains('\\')
+        && key.split('/').all(|part| !part.is_empty() && part != "." && part != "..");
+    if valid {
+        Ok(())
+    } else {
+        Err(invalid("path", "must be a relative path without empty, . or .. segments"))
+    }
+}
+
+/// Fails when `path`, or the deepest part of it that exists, resolves
+/// outside of `base` through a symlink. `base` must be canonical.
+pub fn check_within(base: &Path, path: &Path) -> Result<(), Error> {
+    let mut existing = path;
+    while fs::symlink_metadata(existing).is_err() {
+        existing = match existing.parent() {
+            Some(parent) => parent,
+            None => return Ok(()),
+        };
+    }
+    // a dangling symlink cannot be resolved and is treated as an escape
+    match fs::canonicalize(existing) {
+        Ok(resolved) if resolved.starts_with(base) => Ok(()),
+        _ => Err(Error::new(ErrorKind::Forbidden, "PATH_OUTSIDE_ROOT")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+
+    const ID: &str = "0b16b105-cae1-11f1-98b1-78eca6ee1932";
+
+    #[test]
+    fn user_id_accepts_plain_numbers() {
+        assert_eq!(UserId::parse("0").unwrap().get(), 0);
+        assert_eq!(UserId::parse("12").unwrap().get(), 12);
+        assert_eq!(UserId::parse("2147483647").unwrap().get(), i32::MAX);
+    }
+
+    #[test]
+    fn user_id_rejects_hostile_input() {
+        for input in [
+            "", "..", ".", "../12", "12/..", "/12", "12/", "/", "-1", "+1", "012", " 12", "12 ",
+            "12\0", "\x0012", "1e3", "0x10", "2147483648", "99999999999999999999", "12%2f..", "١٢",
+        ] {
+            let e = UserId::parse(input).expect_err(input);
+            assert_eq!(e.get_status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn image_name_round_trips() {
+        let input = format!("{}_720.jpg", ID);
+        let name = ImageName::parse(&input).unwrap();
+        assert_eq!(name.suffix, "720");
+        assert_eq!(name.ext, "jpg");
+        assert_eq!(name.to_string(), input);
+        assert_eq!(name.key(UserId::parse("12").unwrap()), format!("12/{}", input));
+        let retina = ImageName::parse(&format!("{}_720@2x.png", ID)).unwrap();
+        assert_eq!(retina.suffix, "720@2x");
+        assert_eq!(ImageName::parse(&format!("{}_320.avif", ID)).unwrap().ext, "avif");
+    }
+
+    #[test]
+    fn image_name_rejects_hostile_input() {
+        let v4 = "4b1d3c8e-6f2a-4c1e-9b7d-2a5f8e3c1d0b";
+        for input in [
+            String::new(),
+            "..".to_owned(),
+            "../../etc/passwd".to_owned(),
+            "/etc/passwd".to_owned(),
+            format!("../{}_720.jpg", ID),
+            format!("{}_720.jpg/../../x", ID),
+            format!("{}_720.jpg\0.png", ID),
+            format!("{}\0_720.jpg", ID),
+            format!("{}_720.jpg.", ID),
+            format!("{}_720..jpg", ID),
+            format!("{}_720.gif", ID),
+            format!("{}_720.JPG", ID),
+            format!("{}_7 2.jpg", ID),
+            format!("{}_72/x.jpg", ID),
+            format!("{}_720@.jpg", ID),
+            format!("{}_720@x.jpg", ID),
+            format!("{}_720@2.jpg", ID),
+            format!("{}_720@02x.jpg", ID),
+            format!("{}_@2x.jpg", ID),
+            format!("{}_720@2x@2x.jpg", ID),
+            format!("{}_.jpg", ID),
+            format!("{}.jpg", ID),
+            format!("{}_720.jpg", ID.to_uppercase()),
+            format!("{}_720.jpg", ID.replace('-', "")),
+            format!("{{{}}}_720.jpg", ID),
+            format!("{}_720.jpg", v4),
+            format!("{}_720.tmp.jpg", ID),
+            "x_720.jpg".to_owned(),
+        ] {
+            let e = ImageName::parse(&input).expect_err(&input);
+            assert_eq!(e.get_status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn image_ext_is_an_allow_list() {
+        assert!(image_ext("png").is_ok());
+        for input in ["", "gif", "PNG", "png/", "/png", "png\0", "../png", "svg"] {
+            let e = image_ext(input).expect_err(input);
+            assert_eq!(e.get_status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        }
+    }
+
+    #[test]
+    fn check_key_accepts_relative_keys() {
+        for input in ["12/a_720.jpg", "12/.keep", ".trash/a_720.jpg", "a"] {
+            assert!(check_key(input).is_ok(), "rejected {:?}", input);
+        }
+    }
+
+    #[test]
+    fn check_key_rejects_hostile_input() {
+        for input in [
+            "", ".", "..", "/", "/etc/passwd", "../x", "12/../../x", "12/./x", "12//x", "12/",
+            "12/..", "12\\..\\x", "..\\x", "12/x\0", "\0", "C:\\x",
+        ] {
+            assert!(check_key(input).is_err(), "accepted {:?}", input);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn check_within_rejects_symlink_escapes() {
+        use std::os::unix::fs::symlink;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("root");
+        let outside = tmp.path().join("outside");
+        fs::create_dir_all(root.join("12")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(outside.join("secret"), b"x").unwrap();
+        symlink(&outside, root.join("13")).unwrap();
+        symlink(outside.join("secret"), root.join("12").join("link.jpg")).unwrap();
+        symlink(tmp.path().join("missing"), root.join("12").join("dangling.jpg")).unwrap();
+        let root = fs::canonicalize(&root).unwrap();
+
+        assert!(check_within(&root, &root.join("12")).is_ok());
+        assert!(check_within(&root, &root.join("12/new.jpg")).is_ok());
+        assert!(check_within(&root, &root.join("14/new/deeper.jpg")).is_ok());
+        let e = check_within(&root, &root.join("13")).unwrap_err();
+        assert_eq!(e.get_status(), StatusCode::FORBIDDEN);
+        assert!(check_within(&root, &root.join("13/secret")).is_err());
+        assert!(check_within(&root, &root.join("13/new.jpg")).is_err());
+        assert!(check_within(&root, &root.join("12/link.jpg")).is_err());
+        assert!(check_within(&root, &root.join("12/dangling.jpg")).is_err());
+    }
+}
@@ -0,0 +1,363 @@
+use crate::error::Error;
+use crate::storage::Storage;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use actix_web::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use actix_web::body::SizedStream;
+use bytes::Bytes;
+use futures::stream;
+use actix_web::http::{Method, StatusCode, header::{self, Charset, ContentDisposition, DispositionParam, DispositionType, EntityTag, ExtendedValue, Header, HttpDate}};
+use serde::Deserialize;
+
+/// `?download=1` asks for the file as an attachment instead of inline.
+#[derive(Deserialize)]
+pub struct ServeQuery {
+    #[serde(default)]
+    download: u8,
+}
+
+impl ServeQuery {
+    pub fn download(&self) -> bool {
+        self.download != 0
+    }
+}
+
+/// Name to save a download of `file_name` as: the upload's original
+/// name, sanitized, with the extension of the file actually served.
+/// Falls back to `file_name` when there is no usable original name.
+pub fn download_name(original: Option<&str>, file_name: &str) -> String {
+    let ext = file_name.rsplit_once('.').map(|(_, ext)| ext).unwrap_or_default();
+    let stem = original
+        .map(sanitize_filename::sanitize)
+        .map(|name| match name.rsplit_once('.') {
+            Some((stem, _)) => stem.to_owned(),
+            None => name,
+        })
+        .filter(|stem| !stem.trim_matches('.').trim().is_empty());
+    match stem {
+        Some(stem) => format!("{}.{}", stem, ext),
+        None => file_name.to_owned(),
+    }
+}
+
+/// `attachment` with `filename` for `name`, plus `filename*` when the
+/// name is not plain ASCII.
+fn attachment(name: String) -> ContentDisposition {
+    let ascii: String = name
+        .chars()
+        .map(|c| if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' { c } else { '_' })
+        .collect();
+    let mut parameters = vec![DispositionParam::Filename(ascii.clone())];
+    if ascii != name {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_owned()),
+            language_tag: None,
+            value: name.into_bytes(),
+        }));
+    }
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters,
+    }
+}
+
+/// Response headers of a served file besides the ones taken from storage.
+pub struct ServeOptions<'a> {
+    /// Name to save the file as; `None` serves it inline.
+    pub download: Option<String>,
+    /// `Cache-Control` value, from the `[cache]` policy of the route.
+    pub cache_control: &'a str,
+    /// Hex content hash of the file, sent as a strong `ETag`. Files
+    /// written before hashes were recorded have none.
+    pub hash: Option<&'a str>,
+}
+
+/// Whether a request carrying `If-None-Match` or, failing that,
+/// `If-Modified-Since` already has the current representation.
+fn not_modified(req: &HttpRequest, etag: Option<&EntityTag>, last_modified: Option<HttpDate>) -> bool {
+    if req.headers().contains_key(header::IF_NONE_MATCH) {
+        return match header::IfNoneMatch::parse(req) {
+            Ok(header::IfNoneMatch::Any) => true,
+            Ok(header::IfNoneMatch::Items(tags)) => etag.is_some_and(|etag| tags.iter().any(|tag| tag.weak_eq(etag))),
+            Err(_) => false,
+        };
+    }
+    match (last_modified, header::IfModifiedSince::parse(req)) {
+        (Some(last_modified), Ok(since)) => last_modified <= since.0,
+        _ => false,
+    }
+}
+
+/// Whether a `Range` may be honoured: without `If-Range`, or when it
+/// names the current representation exactly.
+fn range_applies(req: &HttpRequest, etag: Option<&EntityTag>, last_modified: Option<HttpDate>) -> bool {
+    if !req.headers().contains_key(header::IF_RANGE) {
+        return true;
+    }
+    match header::IfRange::parse(req) {
+        Ok(header::IfRange::EntityTag(tag)) => etag.is_some_and(|etag| tag.strong_eq(etag)),
+        Ok(header::IfRange::Date(date)) => last_modified == Some(date),
+        Err(_) => false,
+    }
+}
+
+/// HTTP dates have no fractional seconds; comparing against a file's
+/// exact mtime would never find it unmodified.
+fn whole_seconds(time: SystemTime) -> HttpDate {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default();
+    HttpDate::from(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn validators(res: &mut HttpResponseBuilder, options: &ServeOptions, etag: Option<EntityTag>, last_modified: Option<HttpDate>) {
+    res.insert_header((header::CACHE_CONTROL, options.cache_control));
+    if let Some(etag) = etag {
+        res.insert_header(header::ETag(etag));
+    }
+    if let Some(last_modified) = last_modified {
+        res.insert_header(header::LastModified(last_modified));
+    }
+}
+
+/// Streams `key` out of storage, honouring conditional requests and a
+/// single `Range`. HEAD requests get the same headers without the body.
+pub async fn serve_object(req: &HttpRequest, storage: &dyn Storage, key: &str, options: ServeOptions<'_>) -> Result<HttpResponse, Error> {
+    let mut range = match header::Range::parse(req) {
+        Ok(header::Range::Bytes(ranges)) if ranges.len() == 1 => ranges.into_iter().next(),
+        _ => None,
+    };
+    let etag = options.hash.map(|hash| EntityTag::new_strong(hash.to_owned()));
+    let mut object = storage.stream(key, range.clone()).await?;
+    let last_modified = object.last_modified.map(whole_seconds);
+
+    if not_modified(req, etag.as_ref(), last_modified) {
+        let mut res = HttpResponse::NotModified();
+        validators(&mut res, &options, etag, last_modified);
+        return Ok(res.finish());
+    }
+    // storage already cut the body to the range; after a stale If-Range
+    // the whole file is sent instead
+    if range.is_some() && !range_applies(req, etag.as_ref(), last_modified) {
+        range = None;
+        object = storage.stream(key, None).await?;
+    }
+
+    let ext = key.rsplit('.').next().unwrap_or_default();
+    let mut res = HttpResponse::Ok();
+    res.content_type(actix_files::file_extension_to_mime(ext))
+        .insert_header((header::ACCEPT_RANGES, "bytes"));
+    validators(&mut res, &options, etag, last_modified);
+    match options.download {
+        Some(name) => res.insert_header(attachment(name)),
+        None => res.insert_header(ContentDisposition {
+            disposition: DispositionType::Inline,
+            parameters: vec![],
+        }),
+    };
+
+    match object.range {
+        Some((start, end)) => {
+            res.status(StatusCode::PARTIAL_CONTENT)
+                .insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+                    range: Some((start, end)),
+                    instance_length: Some(object.size),
+                }))
+                .no_chunking(end - start + 1);
+        }
+        None if range.is_some() => {
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .insert_header(header::ContentRange(header::ContentRangeSpec::Bytes {
+                    range: None,
+                    instance_length: Some(object.size),
+                }))
+                .finish());
+        }
+        None => {
+            res.no_chunking(object.size);
+        }
+    }
+    // the declared length without the bytes, and without reading them
+    // from storage
+    if req.method() == Method::HEAD {
+        let length = object.range.map_or(object.size, |(start, end)| end - start + 1);
+        return Ok(res.body(SizedStream::new(length, stream::empty::<Result<Bytes, Error>>())));
+    }
+    Ok(res.streaming(object.body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+    use actix_web::body::{BodySize, MessageBody};
+    use actix_web::test::TestRequest;
+
+    const KEY: &str = "12/a_320.png";
+    const HASH: &str = "c0ffee";
+
+    async fn storage() -> MemoryStorage {
+        let storage = MemoryStorage::default();
+        let data: Vec<u8> = (0..100).collect();
+        storage.put_bytes(KEY, Bytes::from(data)).await.unwrap();
+        storage
+    }
+
+    fn options() -> ServeOptions<'static> {
+        ServeOptions { download: None, cache_control: "public, max-age=86400", hash: Some(HASH) }
+    }
+
+    async fn serve(req: TestRequest) -> HttpResponse {
+        serve_object(&req.to_http_request(), &storage().await, KEY, options()).await.unwrap()
+    }
+
+    async fn body(res: HttpResponse) -> Bytes {
+        actix_web::body::to_bytes(res.into_body()).await.unwrap()
+    }
+
+    fn header(res: &HttpResponse, name: header::HeaderName) -> &str {
+        res.headers().get(name).unwrap().to_str().unwrap()
+    }
+
+    fn a_minute_from_now() -> HttpDate {
+        HttpDate::from(SystemTime::now() + Duration::from_secs(60))
+    }
+
+    #[actix_web::test]
+    async fn serves_the_whole_file_with_validators() {
+        let res = serve(TestRequest::get()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res, header::ETAG), "\"c0ffee\"");
+        assert_eq!(header(&res, header::CONTENT_TYPE), "image/png");
+        assert_eq!(header(&res, header::ACCEPT_RANGES), "bytes");
+        assert!(res.headers().contains_key(header::LAST_MODIFIED));
+        assert_eq!(body(res).await.len(), 100);
+    }
+
+    #[actix_web::test]
+    async fn if_none_match_answers_304() {
+        for tag in ["\"c0ffee\"", "W/\"c0ffee\"", "\"other\", \"c0ffee\"", "*"] {
+            let res = serve(TestRequest::get().insert_header((header::IF_NONE_MATCH, tag))).await;
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED, "{}", tag);
+            assert_eq!(header(&res, header::ETAG), "\"c0ffee\"");
+            assert!(body(res).await.is_empty());
+        }
+        let res = serve(TestRequest::get().insert_header((header::IF_NONE_MATCH, "\"other\""))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn if_modified_since_answers_304() {
+        let res = serve(TestRequest::get().insert_header(header::IfModifiedSince(a_minute_from_now()))).await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        let res = serve(TestRequest::get().insert_header(header::IfModifiedSince(HttpDate::from(UNIX_EPOCH)))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        // If-None-Match wins when both are sent
+        let req = TestRequest::get()
+            .insert_header((header::IF_NONE_MATCH, "\"other\""))
+            .insert_header(header::IfModifiedSince(a_minute_from_now()));
+        assert_eq!(serve(req).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn range_answers_206() {
+        let res = serve(TestRequest::get().insert_header((header::RANGE, "bytes=10-19"))).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(header(&res, header::CONTENT_RANGE), "bytes 10-19/100");
+        assert_eq!(body(res).await, Bytes::from((10..20).collect::<Vec<u8>>()));
+
+        let res = serve(TestRequest::get().insert_header((header::RANGE, "bytes=-5"))).await;
+        assert_eq!(header(&res, header::CONTENT_RANGE), "bytes 95-99/100");
+    }
+
+    #[actix_web::test]
+    async fn unsatisfiable_range_answers_416() {
+        let res = serve(TestRequest::get().insert_header((header::RANGE, "bytes=200-300"))).await;
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(header(&res, header::CONTENT_RANGE), "bytes */100");
+    }
+
+    #[actix_web::test]
+    async fn stale_if_range_sends_the_whole_file() {
+        let req = TestRequest::get()
+            .insert_header((header::RANGE, "bytes=10-19"))
+            .insert_header((header::IF_RANGE, "\"other\""));
+        let res = serve(req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.headers().contains_key(header::CONTENT_RANGE));
+        assert_eq!(body(res).await.len(), 100);
+
+        // a weak tag never matches, a current one does
+        let req = TestRequest::get()
+            .insert_header((header::RANGE, "bytes=10-19"))
+            .insert_header((header::IF_RANGE, "W/\"c0ffee\""));
+        assert_eq!(serve(req).await.status(), StatusCode::OK);
+        let req = TestRequest::get()
+            .insert_header((header::RANGE, "bytes=10-19"))
+            .insert_header((header::IF_RANGE, "\"c0ffee\""));
+        assert_eq!(serve(req).await.status(), StatusCode::PARTIAL_CONTENT);
+    }
+
+    #[actix_web::test]
+    async fn head_has_the_headers_but_no_body() {
+        let res = serve(TestRequest::default().method(Method::HEAD)).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(header(&res, header::ETAG), "\"c0ffee\"");
+        assert_eq!(res.body().size(), BodySize::Sized(100));
+        assert!(body(res).await.is_empty());
+
+        let res = serve(TestRequest::default().method(Method::HEAD).insert_header((header::RANGE, "bytes=10-19"))).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.body().size(), BodySize::Sized(10));
+        assert!(body(res).await.is_empty());
+    }
+
+    #[test]
+    fn download_name_keeps_the_original_stem_with_the_served_ext() {
+        assert_eq!(download_name(Some("holiday.jpg"), "a_720.avif"), "holiday.avif");
+        assert_eq!(download_name(Some("holiday.2023.JPG"), "a_720.webp"), "holiday.2023.webp");
+        assert_eq!(download_name(Some("holiday"), "a_720.jpg"), "holiday.jpg");
+    }
+
+    #[test]
+    fn download_name_sanitizes_the_original() {
+        let name = download_name(Some("../../etc/pass:wd.jpg"), "a_720.png");
+        assert!(!name.contains('/') && !name.contains(':'), "{}", name);
+        assert!(name.ends_with("passwd.png"), "{}", name);
+        assert_eq!(download_name(Some("con.jpg"), "a_720.png").rsplit_once('.').unwrap().1, "png");
+    }
+
+    #[test]
+    fn download_name_falls_back_to_the_file_name() {
+        for original in [None, Some(""), Some("..."), Some(" .jpg"), Some("///")] {
+            assert_eq!(download_name(original, "a_720.png"), "a_720.png", "{:?}", original);
+        }
+    }
+
+    #[test]
+    fn attachment_adds_filename_star_only_when_needed() {
+        assert_eq!(attachment("holiday.jpg".to_owned()).to_string(), "attachment; filename=\"holiday.jpg\"");
+
+        let disposition = attachment("été à Zürich.jpg".to_owned());
+        assert_eq!(
+            disposition.to_string(),
+            "attachment; filename=\"_t_ _ Z_rich.jpg\"; filename*=UTF-8''%C3%A9t%C3%A9%20%C3%A0%20Z%C3%BCrich.jpg"
+        );
+        // quotes and backslashes cannot appear in the quoted `filename`
+        let disposition = attachment("a\"b\\c.jpg".to_owned());
+        assert_eq!(disposition.get_filename(), Some("a_b_c.jpg"));
+        assert!(disposition.get_filename_ext().is_some());
+    }
+
+    #[actix_web::test]
+    async fn download_is_served_as_an_attachment() {
+        let req = TestRequest::get().to_http_request();
+        let options = ServeOptions { download: Some(download_name(Some("Straße.jpeg"), KEY)), ..options() };
+        let res = serve_object(&req, &storage().await, KEY, options).await.unwrap();
+        let disposition = ContentDisposition::from_raw(res.headers().get(header::CONTENT_DISPOSITION).unwrap()).unwrap();
+        assert!(disposition.is_attachment());
+        assert_eq!(disposition.get_filename(), Some("Stra_e.png"));
+        assert_eq!(disposition.get_filename_ext().unwrap().value, "Straße.png".as_bytes());
+
+        let res = serve(TestRequest::get()).await;
+        assert_eq!(header(&res, header::CONTENT_DISPOSITION), "inline");
+    }
+}
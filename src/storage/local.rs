@@ -0,0 +1,221 @@
+use super::{ByteStream, Object, Storage, TRASH_PREFIX};
+use crate::error::Error;
+use crate::safe_path::{check_key, check_within};
+
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use actix_web::{http::header::ByteRangeSpec, web};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{stream, StreamExt};
+
+const READ_CHUNK: u64 = 64 * 1024;
+
+/// Stores objects as files under the images root, with the trash
+/// namespace mapped onto a separate directory. No key resolves outside
+/// of those two, not even through a symlink.
+pub struct LocalStorage {
+    dirs: Arc<Dirs>,
+}
+
+struct Dirs {
+    root: PathBuf,
+    trash: PathBuf,
+}
+
+impl Dirs {
+    fn path(&self, key: &str) -> Result<PathBuf, Error> {
+        check_key(key)?;
+        let (base, rest) = match key.strip_prefix(TRASH_PREFIX) {
+            Some(rest) => (&self.trash, rest),
+            None => (&self.root, key),
+        };
+        let path = base.join(rest);
+        check_within(base, &path)?;
+        Ok(path)
+    }
+
+    /// Directory for `list`, which takes prefixes such as `12/` that are
+    /// not keys themselves.
+    fn dir_path(&self, dir_prefix: &str) -> Result<PathBuf, Error> {
+        match dir_prefix {
+            "" => Ok(self.root.clone()),
+            dir if dir == TRASH_PREFIX => Ok(self.trash.clone()),
+            dir => self.path(dir.trim_end_matches('/')),
+        }
+    }
+}
+
+impl LocalStorage {
+    pub fn new(root: PathBuf, trash: PathBuf) -> Self {
+        LocalStorage {
+            dirs: Arc::new(Dirs {
+                root: fs::canonicalize(&root).unwrap_or(root),
+                trash: fs::canonicalize(&trash).unwrap_or(trash),
+            }),
+        }
+    }
+
+    /// Runs `f` on the blocking thread pool. Every filesystem call blocks,
+    /// resolving a key through `check_within` included.
+    async fn blocking<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Dirs) -> Result<T, Error> + Send + 'static,
+    {
+        let dirs = self.dirs.clone();
+        web::block(move || f(&dirs)).await?
+    }
+}
+
+#[async_trait(?Send)]
+impl Storage for LocalStorage {
+    async fn put(&self, key: &str, mut body: ByteStream<'_>) -> Result<u64, Error> {
+        let key = key.to_owned();
+        let (path, mut file) = self.blocking(move |dirs| {
+            let path = dirs.path(&key)?;
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = File::create(&path)?;
+            Ok((path, file))
+        }).await?;
+        let mut written = 0;
+        while let Some(chunk) = body.next().await {
+            let res = match chunk {
+                Ok(data) => {
+                    written += data.len() as u64;
+                    web::block(move || file.write_all(&data).map(|_| file))
+                        .await
+                        .map_err(Error::from)
+                        .and_then(|res| res.map_err(Error::from))
+                }
+                Err(e) => Err(e),
+            };
+            file = match res {
+                Ok(file) => file,
+                Err(e) => {
+                    let _ = web::block(move || fs::remove_file(path)).await;
+                    return Err(e);
+                }
+            };
+        }
+        Ok(written)
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes, Error> {
+        let key = key.to_owned();
+        let data = self.blocking(move |dirs| Ok(fs::read(dirs.path(&key)?)?)).await?;
+        Ok(Bytes::from(data))
+    }
+
+    async fn stream(&self, key: &str, range: Option<ByteRangeSpec>) -> Result<Object, Error> {
+        let key = key.to_owned();
+        let (file, metadata) = self.blocking(move |dirs| {
+            let file = File::open(dirs.path(&key)?)?;
+            let metadata = file.metadata()?;
+            Ok((file, metadata))
+        }).await?;
+        let size = metadata.len();
+        let range = range.and_then(|r| r.to_satisfiable_range(size));
+        let (start, end) = range.unwrap_or((0, size.saturating_sub(1)));
+        let remaining = if size == 0 { 0 } else { end - start + 1 };
+
+        let body = stream::unfold((Some(file), start, remaining), |(file, offset, remaining)| async move {
+            let file = file?;
+            if remaining == 0 {
+                return None;
+            }
+            let len = remaining.min(READ_CHUNK);
+            let res = web::block(move || -> Result<(File, Vec<u8>), std::io::Error> {
+                let mut file = file;
+                let mut buf = vec![0; len as usize];
+                file.seek(SeekFrom::Start(offset))?;
+                file.read_exact(&mut buf)?;
+                Ok((file, buf))
+            }).await;
+            match res {
+                Ok(Ok((file, buf))) => Some((Ok(Bytes::from(buf)), (Some(file), offset + len, remaining - len))),
+                Ok(Err(e)) => Some((Err(Error::from(e)), (None, offset, 0))),
+                Err(e) => Some((Err(Error::from(e)), (None, offset, 0))),
+            }
+        });
+
+        Ok(Object {
+            size,
+            last_modified: metadata.modified().ok(),
+            range,
+            body: body.boxed_local(),
+        })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        let key = key.to_owned();
+        self.blocking(move |dirs| Ok(fs::remove_file(dirs.path(&key)?)?)).await
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        let (from, to) = (from.to_owned(), to.to_owned());
+        self.blocking(move |dirs| {
+            let from = dirs.path(&from)?;
+            let to = dirs.path(&to)?;
+            if let Some(parent) = to.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            Ok(fs::rename(from, to)?)
+        }).await
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let prefix = prefix.to_owned();
+        self.blocking(move |dirs| {
+            let dir_prefix = match prefix.rfind('/') {
+                Some(i) => &prefix[..=i],
+                None => "",
+            };
+            let dir = dirs.dir_path(dir_prefix)?;
+            if !dir.is_dir() {
+                return Ok(vec![]);
+            }
+            let mut keys = vec![];
+            for entry in fs::read_dir(dir)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_file() {
+                    continue;
+                }
+                if let Some(name) = entry.file_name().to_str() {
+                    let key = format!("{}{}", dir_prefix, name);
+                    if key.starts_with(&prefix) {
+                        keys.push(key);
+                    }
+                }
+            }
+            keys.sort();
+            Ok(keys)
+        }).await
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        let key = key.to_owned();
+        self.blocking(move |dirs| Ok(dirs.path(&key)?.exists())).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn replace_bytes_swaps_the_whole_file() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("images/12")).unwrap();
+        let storage = LocalStorage::new(dir.path().join("images"), dir.path().join("trash"));
+        storage.put_bytes("12/a_720.png", Bytes::from_static(b"old bytes")).await.unwrap();
+        let written = storage.replace_bytes("12/a_720.png", Bytes::from_static(b"new")).await.unwrap();
+        assert_eq!(written, 3);
+        assert_eq!(storage.get("12/a_720.png").await.unwrap(), Bytes::from_static(b"new"));
+        assert_eq!(storage.list("12/").await.unwrap(), ["12/a_720.png"]);
+    }
+}
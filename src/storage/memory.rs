@@ -0,0 +1,98 @@
+use super::{ByteStream, Object, Storage};
+use crate::error::Error;
+
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::time::SystemTime;
+use actix_web::http::header::ByteRangeSpec;
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use futures::{stream, StreamExt};
+
+struct MemoryObject {
+    data: Bytes,
+    modified: SystemTime,
+}
+
+/// Keeps every object in a map. Nothing survives a restart; meant for
+/// tests and throwaway instances.
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: RwLock<BTreeMap<String, MemoryObject>>,
+}
+
+fn not_found(key: &str) -> Error {
+    Error::from(std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} not found", key)))
+}
+
+#[async_trait(?Send)]
+impl Storage for MemoryStorage {
+    async fn put(&self, key: &str, mut body: ByteStream<'_>) -> Result<u64, Error> {
+        let mut data = BytesMut::new();
+        while let Some(chunk) = body.next().await {
+            data.extend_from_slice(&chunk?);
+        }
+        let written = data.len() as u64;
+        self.objects.write().unwrap().insert(key.to_owned(), MemoryObject {
+            data: data.freeze(),
+            modified: SystemTime::now(),
+        });
+        Ok(written)
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes, Error> {
+        match self.objects.read().unwrap().get(key) {
+            Some(object) => Ok(object.data.clone()),
+            None => Err(not_found(key)),
+        }
+    }
+
+    async fn stream(&self, key: &str, range: Option<ByteRangeSpec>) -> Result<Object, Error> {
+        let objects = self.objects.read().unwrap();
+        let object = objects.get(key).ok_or_else(|| not_found(key))?;
+        let size = object.data.len() as u64;
+        let range = range.and_then(|r| r.to_satisfiable_range(size));
+        let data = match range {
+            Some((start, end)) => object.data.slice(start as usize..=end as usize),
+            None => object.data.clone(),
+        };
+        Ok(Object {
+            size,
+            last_modified: Some(object.modified),
+            range,
+            body: stream::once(async { Ok(data) }).boxed_local(),
+        })
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match self.objects.write().unwrap().remove(key) {
+            Some(_) => Ok(()),
+            None => Err(not_found(key)),
+        }
+    }
+
+    async fn rename(&self, from: &str, to: &str) -> Result<(), Error> {
+        let mut objects = self.objects.write().unwrap();
+        let object = objects.remove(from).ok_or_else(|| not_found(from))?;
+        objects.insert(to.to_owned(), object);
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let objects = self.objects.read().unwrap();
+        Ok(objects
+            .range(prefix.to_owned()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(prefix))
+            .filter(|key| !key[prefix.len()..].contains('/'))
+            .cloned()
+            .collect())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        let prefix = format!("{}/", key);
+        let objects = self.objects.read().unwrap();
+        Ok(objects.contains_key(key)
+            || objects.range(prefix.clone()..).next().is_some_and(|(k, _)| k.starts_with(&prefix)))
+    }
+}
@@ -0,0 +1,87 @@
+mod local;
+mod memory;
+mod s3;
+
+pub use self::local::LocalStorage;
+pub use self::memory::MemoryStorage;
+pub use self::s3::S3Storage;
+
+use crate::config::{Config, StorageBackend};
+use crate::error::Error;
+use crate::unique::time_uuid;
+
+use std::sync::Arc;
+use std::time::SystemTime;
+use actix_web::http::header::ByteRangeSpec;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::stream::{self, LocalBoxStream, StreamExt};
+
+/// Key prefix of the trash namespace. User ids are numeric, so it can
+/// never collide with a user directory.
+pub static TRASH_PREFIX: &str = ".trash/";
+
+pub type ByteStream<'a> = LocalBoxStream<'a, Result<Bytes, Error>>;
+
+/// An object opened for reading.
+pub struct Object {
+    /// Full size of the stored object, regardless of `range`.
+    pub size: u64,
+    pub last_modified: Option<SystemTime>,
+    /// Inclusive byte range covered by `body`, when a satisfiable range
+    /// was requested.
+    pub range: Option<(u64, u64)>,
+    pub body: ByteStream<'static>,
+}
+
+/// Where image bytes live. Keys are `/`-separated and relative to the
+/// backend root, e.g. `{userId}/{imgName}_720.{ext}`.
+#[async_trait(?Send)]
+pub trait Storage: Send + Sync {
+    /// Stores `body` under `key`, replacing any existing object, and
+    /// returns the number of bytes written.
+    async fn put(&self, key: &str, body: ByteStream<'_>) -> Result<u64, Error>;
+    async fn get(&self, key: &str) -> Result<Bytes, Error>;
+    async fn stream(&self, key: &str, range: Option<ByteRangeSpec>) -> Result<Object, Error>;
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+    async fn rename(&self, from: &str, to: &str) -> Result<(), Error>;
+    /// Keys starting with `prefix`, without descending past the next `/`.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error>;
+    async fn exists(&self, key: &str) -> Result<bool, Error>;
+
+    async fn put_bytes(&self, key: &str, data: Bytes) -> Result<u64, Error> {
+        self.put(key, stream::once(async { Ok(data) }).boxed_local()).await
+    }
+
+    /// Like `put_bytes`, but a reader of `key` gets either the old object
+    /// or the whole new one, never a partly written one: the data goes to
+    /// a temporary key first and is renamed into place.
+    async fn replace_bytes(&self, key: &str, data: Bytes) -> Result<u64, Error> {
+        let tmp = format!("{}.{}.tmp", key, time_uuid());
+        let replaced = match self.put_bytes(&tmp, data).await {
+            Ok(written) => self.rename(&tmp, key).await.map(|_| written),
+            Err(e) => Err(e),
+        };
+        if replaced.is_err() && self.exists(&tmp).await.unwrap_or(false) {
+            if let Err(e) = self.delete(&tmp).await {
+                log::error!("could not remove {}: {}", tmp, e);
+            }
+        }
+        replaced
+    }
+}
+
+pub fn trash_key(file_name: &str) -> String {
+    format!("{}{}", TRASH_PREFIX, file_name)
+}
+
+pub fn from_config(config: &Config) -> Arc<dyn Storage> {
+    match config.storage.backend {
+        StorageBackend::Local => Arc::new(LocalStorage::new(
+            config.paths.images.clone(),
+            config.paths.trash.clone(),
+        )),
+        StorageBackend::Memory => Arc::new(MemoryStorage::default()),
+        StorageBackend::S3 => Arc::new(S3Storage::new(config.storage.s3.clone())),
+    }
+}
@@ -10,4 +10,16 @@ pub fn time_uuid() -> Uuid {
     let utc: DateTime<Utc> = Utc::now(); 
     let ts = Timestamp::from_unix(&context, utc.timestamp() as u64, utc.timestamp_subsec_nanos());
     Uuid::new_v1(ts, &rand).expect("failed to generate UUID")
-}
\ No newline at end of file
+}
+
+/// 100ns ticks since 1582-10-15 carried by a v1 UUID, `None` for any
+/// other version. Orders ids the same way Scylla orders `timeuuid`.
+pub fn time_ticks(id: &Uuid) -> Option<u64> {
+    id.to_timestamp().map(|ts| ts.to_rfc4122().0)
+}
+
+/// Creation time carried by a v1 UUID, `None` for any other version.
+pub fn uuid_time(id: &Uuid) -> Option<DateTime<Utc>> {
+    let (secs, nanos) = id.to_timestamp()?.to_unix();
+    Utc.timestamp_opt(secs as i64, nanos).single()
+}
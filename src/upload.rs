@@ -1,27 +1,51 @@
 use crate::unique::time_uuid;
-use crate::error::Error;
-use crate::{PATH, TRASH};
-
-use actix_web::{HttpResponse, web};
+use crate::error::{Error, ErrorKind};
+use crate::storage::{trash_key, Storage};
+use crate::catalog::{Crop, ImageCatalog, ImageRecord, ImageStatus, VariantRecord};
+use crate::auth::AuthSession;
+use crate::avif::{AvifEncoder, PendingVariant, AVIF_EXT};
+use crate::config::{Config, FitMode, LimitsConfig, ResizeEngine, VariantProfile};
+use crate::list_images::{variant_items, VariantItem};
+use crate::resize;
+use crate::safe_path::{image_ext, image_id};
+use crate::workers::WorkerPool;
+
+use actix_web::{HttpRequest, HttpResponse, http::header, web};
 use actix_multipart::{Multipart, Field};
-use futures::{StreamExt, TryStreamExt};
-use std::{io::Write, path::Path, fs};
-use serde::{Deserialize, Serialize};
-use image::{self, imageops::{self, FilterType}};
+use bytes::Bytes;
+use futures::{future, StreamExt, TryStreamExt};
+use std::io::Cursor;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use image::{self, DynamicImage, ImageError, ImageFormat, ImageOutputFormat, imageops};
+use image::io::{Limits, Reader};
+use rayon::prelude::*;
+use uuid::Uuid;
 
 #[derive(Serialize, Deserialize)]
+#[allow(dead_code)]
 pub struct UserRequest {
     user_id: i32,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize)]
 #[allow(non_snake_case)]
-struct UploadResponse {
+pub(crate) struct UploadResponse {
     imgName: String,
     imgExt: String,
-    imgMd: Option<u32>, 
-    imgSm: Option<u32>,
-    imgLg: Option<u32>,
+    variants: Vec<VariantItem>,
+}
+
+impl UploadResponse {
+    pub(crate) fn new(record: &ImageRecord) -> Self {
+        UploadResponse {
+            imgName: record.image_id.to_string(),
+            imgExt: record.ext.clone(),
+            variants: variant_items(record),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -31,7 +55,9 @@ struct RequestMetadata {
     yAxis: u32,
     imgWidth: u32,
     imgHeight: u32,
-    userId: i32
+    /// Only admins may name a user other than themselves.
+    #[serde(default)]
+    userId: Option<i32>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -41,30 +67,66 @@ struct RequestMetadataUpdate {
     yAxis: u32,
     imgWidth: u32,
     imgHeight: u32,
-    userId: i32,
+    #[serde(default)]
+    userId: Option<i32>,
     imgExt: String,
     imgName: String,
 }
 
-impl RequestMetadataUpdate {
-    fn move_trash(&self) -> Result<(), std::io::Error> {
-        let from_sm = format!("{}/{}/{}_320.{}", PATH, &self.userId, &self.imgName, &self.imgExt);
-        let from_md = format!("{}/{}/{}_720.{}", PATH, &self.userId, &self.imgName, &self.imgExt);
-        let to_sm = format!("{}/{}_320.{}", TRASH, &self.imgName, &self.imgExt);
-        let to_md = format!("{}/{}_720.{}", TRASH, &self.imgName, &self.imgExt);
-        fs::rename(from_sm, to_sm)?;
-        fs::rename(from_md, to_md)?;
-        Ok(())
+impl RequestMetadata {
+    fn crop(&self) -> Crop {
+        Crop { x: self.xAxis, y: self.yAxis, width: self.imgWidth, height: self.imgHeight }
     }
 }
 
-impl RequestMetadata {
-    fn create_dir(&self) -> Result<(), Error> {
-        let user_dir = format!("{}/{}", PATH, &self.userId);
-        let is_user_dir: bool = Path::new(&user_dir).is_dir();
-        if !is_user_dir {
-            std::fs::create_dir(&user_dir)?;
+/// Variants written before images were recorded in the catalog.
+const LEGACY_SUFFIXES: [&str; 2] = ["720", "320"];
+
+impl RequestMetadataUpdate {
+    fn crop(&self) -> Crop {
+        Crop { x: self.xAxis, y: self.yAxis, width: self.imgWidth, height: self.imgHeight }
+    }
+
+    /// Files of the image being replaced, relative to the owner's
+    /// directory: its source and the variants listed in its catalog
+    /// record, or else the `_720` and `_320` files every image had
+    /// before there was a catalog. An image that is gone or already
+    /// replaced is a 404, found before anything new is stored.
+    async fn old_files(&self, storage: &dyn Storage, catalog: &dyn ImageCatalog, owner: i32) -> Result<Vec<String>, Error> {
+        let not_found = || Error::new(ErrorKind::NotFound, "image not found.");
+        match catalog.get(owner, image_id(&self.imgName)?).await? {
+            Some(record) if record.status == ImageStatus::Active => Ok(record
+                .variants
+                .iter()
+                .map(|v| record.variant_file_name(v))
+                .chain(record.source_file_name())
+                .collect()),
+            Some(_) => Err(not_found().with_field("imgName", "was already replaced")),
+            None => {
+                let mut file_names = vec![];
+                for suffix in LEGACY_SUFFIXES {
+                    let file_name = variant_file_name(&self.imgName, suffix, &self.imgExt);
+                    if storage.exists(&format!("{}/{}", owner, file_name)).await? {
+                        file_names.push(file_name);
+                    }
+                }
+                if file_names.is_empty() {
+                    return Err(not_found());
+                }
+                Ok(file_names)
+            }
         }
+    }
+
+    async fn trash_record(&self, catalog: &dyn ImageCatalog, owner: i32) -> Result<(), Error> {
+        let image_id = Uuid::parse_str(&self.imgName)?;
+        catalog.set_status(owner, image_id, ImageStatus::Trashed).await
+    }
+
+    /// `imgName` and `imgExt` end up in storage keys.
+    fn validate(&self) -> Result<(), Error> {
+        image_id(&self.imgName)?;
+        image_ext(&self.imgExt)?;
         Ok(())
     }
 }
@@ -73,8 +135,12 @@ impl RequestMetadata {
 #[allow(non_snake_case)]
 struct ImageProps {
     imgName: String,
-    imgExt: String,
-    tmpPath: String,
+    tmpKey: String,
+    originalName: String,
+}
+
+pub(crate) fn variant_file_name(img_name: &str, suffix: &str, img_ext: &str) -> String {
+    format!("{}_{}.{}", img_name, suffix, img_ext)
 }
 
 fn create_url(field: &mut Field, user_id: i32) -> Result<ImageProps, Error> {
@@ -83,185 +149,848 @@ fn create_url(field: &mut Field, user_id: i32) -> Result<ImageProps, Error> {
     let meta_filename = match meta_filename {
         Some(r) => r,
         None => {
-            return Err(Error::from("could not get metadata filename.").into());
+            return Err(Error::new(ErrorKind::InvalidRequest, "could not get metadata filename.")
+                .with_field("image", "filename is missing"));
         }
     };
-    let split_filename: Vec<&str> = meta_filename.split('.').collect();
-    if split_filename.len() != 2 {
-        return Err(Error::from("error in splitting filename.").into());
-    }
     let img_name = time_uuid().to_string();
-    let img_ext = split_filename[1].to_owned();
-    let tmp_path = format!("{}/{}/{}.tmp.{}", PATH, user_id, &img_name, &img_ext);
-
-    match img_ext.as_str() {
-        "jpg" | "jpeg" | "png" => {},
-        _ => {
-            return Err(Error::from("INVALID_EXT")); 
-        }
-    }
+    // the format is only known once the bytes are in
+    let tmp_key = format!("{}/{}.tmp", user_id, &img_name);
 
     Ok(ImageProps {
         imgName: img_name,
-        imgExt: img_ext,
-        tmpPath: tmp_path
+        tmpKey: tmp_key,
+        originalName: meta_filename.to_owned(),
     })
 }
 
-async fn parse_image(field: &mut Field, path: &str) -> Result<(), Error> {
-    let mut f = std::fs::File::create(&path);
+/// Canonical extension of the image in `data`, going by its magic bytes
+/// rather than the client's `file_name`. Content that is not JPEG, PNG
+/// or WebP, or that a known image extension on `file_name` misnames,
+/// is a 415.
+fn sniff_ext(data: &[u8], file_name: &str) -> Result<&'static str, Error> {
+    let unsupported = || {
+        Error::new(ErrorKind::UnsupportedMediaType, "UNSUPPORTED_IMAGE")
+            .with_field("image", "must be a JPEG, PNG or WebP image")
+    };
+    let format = image::guess_format(data).map_err(|_| unsupported())?;
+    let ext = match format {
+        ImageFormat::Jpeg => "jpg",
+        ImageFormat::Png => "png",
+        ImageFormat::WebP => "webp",
+        _ => return Err(unsupported()),
+    };
+    let named = Path::new(file_name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .and_then(ImageFormat::from_extension);
+    match named {
+        Some(named) if named != format => Err(Error::new(ErrorKind::UnsupportedMediaType, "CONTENT_MISMATCH")
+            .with_field("image", format!("is {} but named {}", ext, file_name))),
+        _ => Ok(ext),
+    }
+}
+
+pub(crate) fn too_large(field: &str, max: u64) -> Error {
+    Error::new(ErrorKind::PayloadTooLarge, format!("{} is too large.", field))
+        .with_field(field, format!("must be at most {} bytes", max))
+}
+
+/// Refuses an upload whose declared size is over the limit before any
+/// of it is read. Chunked bodies are bounded by the per-part limits.
+pub(crate) fn check_request_size(req: &HttpRequest, limits: &LimitsConfig) -> Result<(), Error> {
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    match length {
+        Some(length) if length > limits.max_request_bytes => Err(too_large("request", limits.max_request_bytes)),
+        _ => Ok(()),
+    }
+}
 
-    let mut done = false;
+/// Removes what is left of a failed upload.
+async fn discard(storage: &dyn Storage, key: &str) {
+    let removed = match storage.exists(key).await {
+        Ok(true) => storage.delete(key).await,
+        other => other.map(|_| ()),
+    };
+    if let Err(e) = removed {
+        log::error!("could not remove {}: {}", key, e);
+    }
+}
+
+async fn parse_image(field: &mut Field, storage: &dyn Storage, key: &str, limits: &LimitsConfig) -> Result<(), Error> {
     // Field in turn is stream of *Bytes* object
-    while let Some(chunk) = field.next().await {
-        let data = chunk?;
-        // filesystem operations are blocking, we have to use threadpool
-        f = web::block(move || -> Result<std::fs::File, std::io::Error> {
-            let mut g = f?; 
-            g.write_all(&data)?;
-            Ok(g)
-        }).await?;
-        done = true;
-    }
-    if done == false {
-        return Err(Error::from("Could not save image.").into());
+    let max = limits.max_image_bytes;
+    let mut received = 0;
+    let body = field
+        .map_err(Error::from)
+        .and_then(move |chunk| {
+            received += chunk.len() as u64;
+            future::ready(if received > max { Err(too_large("image", max)) } else { Ok(chunk) })
+        })
+        .boxed_local();
+    let written = match storage.put(key, body).await {
+        Ok(written) => written,
+        Err(e) => {
+            discard(storage, key).await;
+            return Err(e);
+        }
+    };
+    if written == 0 {
+        storage.delete(key).await?;
+        return Err(Error::new(ErrorKind::InvalidRequest, "Could not save image.").with_field("image", "is empty"));
     }
     Ok(())
 }
 
-async fn parse_metadata(field: &mut Field) -> Result<Option<RequestMetadata>, Error> {
-    let mut value: Option<RequestMetadata> = None;
+async fn parse_metadata<T: DeserializeOwned>(field: &mut Field, limits: &LimitsConfig) -> Result<Option<T>, Error> {
+    let mut data = Vec::new();
     while let Some(chunk) = field.next().await {
-        let data = chunk?;
-        let v = serde_json::from_str(&String::from_utf8_lossy(&data).to_string())?;
-        value = Some(v);
+        data.extend_from_slice(&chunk?);
+        if data.len() as u64 > limits.max_metadata_bytes {
+            return Err(too_large("metadata", limits.max_metadata_bytes));
+        }
     }
-    Ok(value)
+    if data.is_empty() {
+        return Ok(None);
+    }
+    serde_json::from_slice(&data).map(Some).map_err(|e| {
+        Error::new(ErrorKind::InvalidRequest, "metadata is not valid JSON.").with_field("metadata", e.to_string())
+    })
 }
 
-async fn parse_metadata_update(field: &mut Field) -> Result<Option<RequestMetadataUpdate>, Error> {
-    let mut value: Option<RequestMetadataUpdate> = None;
-    while let Some(chunk) = field.next().await {
-        let data = chunk?;
-        let v = serde_json::from_str(&String::from_utf8_lossy(&data).to_string())?;
-        value = Some(v);
+fn encode(img: &image::RgbaImage, ext: &str, profile: &VariantProfile) -> Result<Vec<u8>, Error> {
+    let format = match ImageFormat::from_extension(ext).ok_or("INVALID_EXT")? {
+        ImageFormat::WebP => {
+            let encoder = webp::Encoder::from_rgba(img.as_raw(), img.width(), img.height());
+            let data = if profile.lossless {
+                encoder.encode_lossless()
+            } else {
+                encoder.encode(f32::from(profile.quality))
+            };
+            if data.is_empty() {
+                return Err(Error::from("could not encode WebP variant"));
+            }
+            return Ok(data.to_vec());
+        }
+        ImageFormat::Jpeg => ImageOutputFormat::Jpeg(profile.quality),
+        format => format.into(),
+    };
+    let mut buf = Cursor::new(Vec::new());
+    img.write_to(&mut buf, format)?;
+    Ok(buf.into_inner())
+}
+
+/// `x * num / den`, rounded to the nearest pixel and at least 1.
+fn scale(x: u32, num: u32, den: u32) -> u32 {
+    let scaled = (u64::from(x) * u64::from(num) + u64::from(den) / 2) / u64::from(den);
+    scaled.max(1) as u32
+}
+
+/// Size of `profile`'s variant of a `w` x `h` crop at `density`: scaled
+/// down until it fits the profile's bounds times `density`, keeping the
+/// aspect ratio. Crops that already fit are kept as they are, never
+/// scaled up.
+fn variant_size(profile: &VariantProfile, density: u32, w: u32, h: u32) -> (u32, u32) {
+    let max_width = profile.width * density;
+    let max_height = match profile.fit {
+        FitMode::Inside => profile.height.map(|height| height * density),
+        FitMode::Width => None,
+    };
+    match max_height {
+        // the height is the tighter bound when height / h < width / w
+        Some(max_height) if u64::from(max_height) * u64::from(w) < u64::from(max_width) * u64::from(h) => {
+            if h <= max_height {
+                (w, h)
+            } else {
+                (scale(w, max_height, h), max_height)
+            }
+        }
+        _ => {
+            if w <= max_width {
+                (w, h)
+            } else {
+                (max_width, scale(h, max_width, w))
+            }
+        }
+    }
+}
+
+pub(crate) struct EncodedVariant {
+    name: String,
+    suffix: String,
+    ext: String,
+    density: u32,
+    width: u32,
+    height: u32,
+    data: Vec<u8>,
+}
+
+/// Time spent in each stage of processing one image, in order. Sent
+/// back to the client as `Server-Timing`.
+#[derive(Default, Debug)]
+pub(crate) struct Timings(Vec<(&'static str, Duration)>);
+
+impl Timings {
+    pub fn add(&mut self, stage: &'static str, elapsed: Duration) {
+        self.0.push((stage, elapsed));
+    }
+
+    fn append(&mut self, other: Timings) {
+        self.0.extend(other.0);
+    }
+
+    fn time<T>(&mut self, stage: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(stage, start.elapsed());
+        result
+    }
+
+    /// e.g. `decode;dur=41.2, resize;dur=96.0`, in milliseconds.
+    pub fn server_timing(&self) -> String {
+        self.0
+            .iter()
+            .map(|(stage, elapsed)| format!("{};dur={:.1}", stage, elapsed.as_secs_f64() * 1000.0))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// One size to scale the crop to: a profile at one density.
+struct Target<'a> {
+    profile: &'a VariantProfile,
+    density: u32,
+    size: (u32, u32),
+    /// Index of the larger target this one is scaled down from; `None`
+    /// scales the crop itself.
+    base: Option<usize>,
+    /// How many scaling steps lie between the crop and this target.
+    depth: usize,
+}
+
+impl Target<'_> {
+    fn suffix(&self) -> String {
+        match self.density {
+            1 => self.profile.suffix.clone(),
+            density => format!("{}@{}x", self.profile.suffix, density),
+        }
+    }
+}
+
+/// Sizes of every variant of a `w` x `h` crop, in profile order. Each is
+/// scaled down from the smallest larger target that is at least twice
+/// its size both ways and made with the same filter and sharpening:
+/// cheaper than going back to the crop, and at that ratio it looks the
+/// same. Targets of profiles set up differently never share a base, so
+/// one profile's settings cannot change another's output. A density
+/// whose variant would be no larger than the previous one, because the
+/// crop is too small, is skipped.
+fn plan(profiles: &[VariantProfile], w: u32, h: u32) -> Vec<Target<'_>> {
+    let mut targets: Vec<Target> = vec![];
+    for profile in profiles {
+        let mut previous = None;
+        for density in std::iter::once(1).chain(profile.densities.iter().copied()) {
+            let size = variant_size(profile, density, w, h);
+            if previous == Some(size) {
+                break;
+            }
+            previous = Some(size);
+            targets.push(Target { profile, density, size, base: None, depth: 0 });
+        }
+    }
+    for i in 0..targets.len() {
+        let (width, height) = targets[i].size;
+        let profile = targets[i].profile;
+        let base = (0..targets.len())
+            .filter(|&j| {
+                let (base_width, base_height) = targets[j].size;
+                let alike = targets[j].profile.filter == profile.filter && targets[j].profile.sharpen == profile.sharpen;
+                alike && base_width >= 2 * width && base_height >= 2 * height && (base_width, base_height) != (w, h)
+            })
+            .min_by_key(|&j| u64::from(targets[j].size.0) * u64::from(targets[j].size.1));
+        targets[i].base = base;
+    }
+    // a base is larger than what is made from it, so going from large
+    // to small sees every base before its targets
+    let mut by_area: Vec<usize> = (0..targets.len()).collect();
+    by_area.sort_by_key(|&i| std::cmp::Reverse(u64::from(targets[i].size.0) * u64::from(targets[i].size.1)));
+    for i in by_area {
+        targets[i].depth = targets[i].base.map_or(0, |j| targets[j].depth + 1);
+    }
+    targets
+}
+
+/// `base` scaled to the size of `target`.
+fn scale_to(base: &image::RgbaImage, target: &Target, engine: ResizeEngine) -> image::RgbaImage {
+    let (width, height) = target.size;
+    let filter = target.profile.filter.into();
+    match engine {
+        _ if base.dimensions() == target.size => base.clone(),
+        ResizeEngine::Imageops => imageops::resize(base, width, height, filter),
+        ResizeEngine::Fast => resize::resize(base, width, height, filter),
+    }
+}
+
+/// Scales every target, one depth at a time so that each base is ready
+/// before the targets derived from it, the targets of a depth in
+/// parallel. The results are not sharpened, so they can serve as bases.
+/// A target whose base is not ready, or that no depth reached, is scaled
+/// from the crop instead; `plan` does not make such targets.
+fn scale_all(crop: &image::RgbaImage, targets: &[Target], engine: ResizeEngine) -> Vec<image::RgbaImage> {
+    let mut scaled: Vec<Option<image::RgbaImage>> = vec![None; targets.len()];
+    let max_depth = targets.iter().map(|t| t.depth).max().unwrap_or_default();
+    for depth in 0..=max_depth {
+        let level: Vec<(usize, image::RgbaImage)> = targets
+            .par_iter()
+            .enumerate()
+            .filter(|(_, target)| target.depth == depth)
+            .map(|(i, target)| {
+                let base = target.base.and_then(|j| scaled[j].as_ref()).unwrap_or(crop);
+                (i, scale_to(base, target, engine))
+            })
+            .collect();
+        for (i, img) in level {
+            scaled[i] = Some(img);
+        }
+    }
+    scaled
+        .into_iter()
+        .zip(targets)
+        .map(|(img, target)| img.unwrap_or_else(|| scale_to(crop, target, engine)))
+        .collect()
+}
+
+pub(crate) struct CroppedImage {
+    pub variants: Vec<EncodedVariant>,
+    /// AVIF variants, encoded later by `AvifEncoder::spawn`.
+    pub pending: Vec<PendingVariant>,
+    pub timings: Timings,
+}
+
+/// Decodes `source` unless its header announces more pixels than
+/// `limits` allow; those are refused before any pixel is decoded.
+pub(crate) fn decode(source: &[u8], format: ImageFormat, limits: &LimitsConfig) -> Result<DynamicImage, Error> {
+    let reader = || {
+        let mut io_limits = Limits::default();
+        io_limits.max_image_width = Some(limits.max_width);
+        io_limits.max_image_height = Some(limits.max_height);
+        // 8 bytes a pixel covers 16-bit RGBA, the widest layout decoded
+        io_limits.max_alloc = Some(limits.max_pixels.saturating_mul(8));
+        let mut reader = Reader::with_format(Cursor::new(source), format);
+        reader.limits(io_limits);
+        reader
+    };
+    // whatever fails while decoding is down to the uploaded bytes
+    let rejected = |e: ImageError| {
+        let message = match e {
+            ImageError::Limits(_) => format!("exceeds {}x{} pixels", limits.max_width, limits.max_height),
+            _ => "could not be decoded".to_owned(),
+        };
+        Error::new(ErrorKind::UnprocessableImage, e.to_string()).with_field("image", message)
+    };
+    let (width, height) = reader().into_dimensions().map_err(rejected)?;
+    if u64::from(width) * u64::from(height) > limits.max_pixels {
+        return Err(Error::new(ErrorKind::UnprocessableImage, "image has too many pixels.")
+            .with_field("image", format!("is {}x{}, more than {} pixels", width, height, limits.max_pixels)));
     }
-    Ok(value)
+    reader().decode().map_err(rejected)
 }
 
-fn crop_image(img_props: &ImageProps, x: u32, y: u32, w: u32, h: u32, user_id: i32) -> Result<(u32, u32), Error> {
-    let mut img = image::open(&img_props.tmpPath)?;
-    let subimg = imageops::crop(&mut img, x, y, w, h);
-    let d = subimg.to_image();
-    let mut width_720 = w;
-    let mut height_720 = h;
-    let mut width_320 = w;
-    let mut height_320 = h;
-    if width_720 > 720 && height_720 > 576 {
-        let crop_width_720 = (720*100)/width_720;
-        width_720 = 720;
-        height_720 = (height_720*crop_width_720)/100;
+/// Crops the source and encodes one variant per profile, density and
+/// format. The source is decoded once; scaling and encoding of the
+/// variants run in parallel on the current rayon pool.
+pub(crate) fn crop_image(source: &[u8], source_ext: &str, crop: Crop, profiles: &[VariantProfile], limits: &LimitsConfig, engine: ResizeEngine) -> Result<CroppedImage, Error> {
+    let mut timings = Timings::default();
+    let format = ImageFormat::from_extension(source_ext).ok_or("INVALID_EXT")?;
+    let mut img = timings.time("decode", || decode(source, format, limits))?;
+    // `crop` clamps the rectangle to the image
+    let d = timings.time("crop", || imageops::crop(&mut img, crop.x, crop.y, crop.width, crop.height).to_image());
+    if d.width() == 0 || d.height() == 0 {
+        return Err(Error::new(ErrorKind::InvalidRequest, "nothing to crop.")
+            .with_field("metadata", "crop area is empty or outside the image"));
+    }
 
+    let targets = plan(profiles, d.width(), d.height());
+    let resized: Vec<image::RgbaImage> = timings.time("resize", || {
+        let scaled = scale_all(&d, &targets, engine);
+        // sharpening is applied last, only to images that were scaled
+        targets
+            .par_iter()
+            .zip(scaled)
+            .map(|(target, img)| match target.profile.sharpen {
+                Some(sharpen) if target.size != d.dimensions() => imageops::unsharpen(&img, sharpen.sigma, sharpen.threshold),
+                _ => img,
+            })
+            .collect()
+    });
+
+    let mut jobs = vec![];
+    let mut pending = vec![];
+    for (target, img) in targets.iter().zip(&resized) {
+        // "original" may name the same format as another entry
+        let mut exts: Vec<&str> = vec![];
+        for format in &target.profile.formats {
+            let ext = format.ext(source_ext);
+            if !exts.contains(&ext) {
+                exts.push(ext);
+            }
+        }
+        for ext in exts {
+            if ext == AVIF_EXT {
+                pending.push(PendingVariant {
+                    name: target.profile.name.clone(),
+                    suffix: target.suffix(),
+                    density: target.density,
+                    image: img.clone(),
+                });
+            } else {
+                jobs.push((target, img, ext));
+            }
+        }
+    }
+    let variants = timings.time("encode", || {
+        jobs.par_iter()
+            .map(|(target, img, ext)| {
+                Ok(EncodedVariant {
+                    name: target.profile.name.clone(),
+                    suffix: target.suffix(),
+                    ext: ext.to_string(),
+                    density: target.density,
+                    width: img.width(),
+                    height: img.height(),
+                    data: encode(img, ext, target.profile)?,
+                })
+            })
+            .collect::<Result<Vec<_>, Error>>()
+    })?;
+    Ok(CroppedImage { variants, pending, timings })
+}
+
+/// Writes `variants` of `img_name` to `user_id`'s directory. Files that
+/// are already there, when reprocessing, are replaced whole.
+pub(crate) async fn store_variants(storage: &dyn Storage, user_id: i32, img_name: &str, variants: Vec<EncodedVariant>) -> Result<Vec<VariantRecord>, Error> {
+    let mut records = vec![];
+    for variant in variants {
+        let key = format!("{}/{}", user_id, variant_file_name(img_name, &variant.suffix, &variant.ext));
+        let hash = hex::encode(Sha256::digest(&variant.data));
+        let bytes = storage.replace_bytes(&key, Bytes::from(variant.data)).await?;
+        records.push(VariantRecord {
+            name: variant.name,
+            suffix: variant.suffix,
+            ext: variant.ext,
+            density: variant.density,
+            width: variant.width,
+            height: variant.height,
+            bytes,
+            hash,
+        });
+    }
+    Ok(records)
+}
 
-        let crop_width_320 = (320*100)/width_320;
-        width_320 = 320;
-        height_320 = (height_320*crop_width_320)/100;
+/// Moves `file_names` of `owner`'s to the trash. If one cannot be
+/// moved, those already moved are put back.
+async fn move_trash(storage: &dyn Storage, owner: i32, file_names: &[String]) -> Result<(), Error> {
+    for (i, file_name) in file_names.iter().enumerate() {
+        if let Err(e) = storage.rename(&format!("{}/{}", owner, file_name), &trash_key(file_name)).await {
+            restore_trash(storage, owner, &file_names[..i]).await;
+            return Err(e);
+        }
     }
-    let x = image::imageops::resize(&d, width_720, height_720, FilterType::Nearest);
-    x.save(format!("{}/{}/{}_720.{}", PATH, user_id, &img_props.imgName, &img_props.imgExt))?;
+    Ok(())
+}
 
-    let y = image::imageops::resize(&d, width_320, height_320, FilterType::Nearest);
-    y.save(format!("{}/{}/{}_320.{}", PATH, user_id, &img_props.imgName, &img_props.imgExt))?;
+/// Puts `file_names` moved by `move_trash` back in `owner`'s directory.
+async fn restore_trash(storage: &dyn Storage, owner: i32, file_names: &[String]) {
+    for file_name in file_names {
+        if let Err(e) = storage.rename(&trash_key(file_name), &format!("{}/{}", owner, file_name)).await {
+            log::error!("could not restore {}/{}: {}", owner, file_name, e);
+        }
+    }
+}
 
-    fs::remove_file(&img_props.tmpPath)?;
+/// Removes the files of a `record` that never made it into the catalog.
+async fn discard_record(storage: &dyn Storage, record: &ImageRecord) {
+    let files = record.variants.iter().map(|v| record.variant_file_name(v)).chain(record.source_file_name());
+    for file_name in files {
+        discard(storage, &format!("{}/{}", record.user_id, file_name)).await;
+    }
+}
 
-    Ok((height_720, height_320))
+/// Reads the temp upload back, writes its variants and keeps it as the
+/// image's source. Returns the catalog record describing what was stored
+/// and the variants still to be encoded, and how long each stage took.
+async fn process_image(storage: &dyn Storage, workers: &WorkerPool, img_props: &ImageProps, crop: Crop, user_id: i32, config: &Config) -> Result<(ImageRecord, Vec<PendingVariant>, Timings), Error> {
+    let source = storage.get(&img_props.tmpKey).await?;
+    let original_name = img_props.originalName.clone();
+    let profiles = config.variants.clone();
+    let limits = config.limits;
+    let engine = config.resize.engine;
+    let queued = Instant::now();
+    let (ext, content_hash, mut cropped, mut timings) = workers
+        .run(move || {
+            let mut timings = Timings::default();
+            timings.add("queue", queued.elapsed());
+            let (ext, content_hash) = timings.time("sniff", || {
+                sniff_ext(&source, &original_name).map(|ext| (ext, hex::encode(Sha256::digest(&source))))
+            })?;
+            let cropped = crop_image(&source, ext, crop, &profiles, &limits, engine)?;
+            Ok((ext, content_hash, cropped, timings))
+        })
+        .await?;
+    timings.append(std::mem::take(&mut cropped.timings));
+    let stored = Instant::now();
+    let records = store_variants(storage, user_id, &img_props.imgName, cropped.variants).await?;
+    let record = ImageRecord {
+        user_id,
+        image_id: Uuid::parse_str(&img_props.imgName)?,
+        ext: ext.to_owned(),
+        variants: records,
+        content_hash,
+        original_filename: img_props.originalName.clone(),
+        status: ImageStatus::Active,
+        crop: Some(crop),
+    };
+    if let Some(source_file) = record.source_file_name() {
+        storage.rename(&img_props.tmpKey, &format!("{}/{}", user_id, source_file)).await?;
+    }
+    timings.add("store", stored.elapsed());
+    log::debug!("processed {}/{}: {}", user_id, img_props.imgName, timings.server_timing());
+    Ok((record, cropped.pending, timings))
 }
 
-pub async fn upload_image(mut payload: Multipart) -> Result<HttpResponse, Error> {
+pub async fn upload_image(req: HttpRequest, config: web::Data<Config>, workers: web::Data<WorkerPool>, avif: web::Data<AvifEncoder>, storage: web::Data<dyn Storage>, catalog: web::Data<dyn ImageCatalog>, mut payload: Multipart) -> Result<HttpResponse, Error> {
+    let user = req.user_info()?;
+    check_request_size(&req, &config.limits)?;
 
     let mut image_data: Option<ImageProps> = None;
     let mut metadata: Option<RequestMetadata> = None;
 
     if let Some(mut field) = payload.try_next().await? {
-        metadata = parse_metadata(&mut field).await?;
+        metadata = parse_metadata(&mut field, &config.limits).await?;
     }
+    let owner = match &metadata {
+        Some(me) => user.owner(me.userId, "upload_image")?,
+        None => user.userId,
+    };
 
     if let Some(mut field) = payload.try_next().await? {
-        if let Some(me) = &metadata {
-            me.create_dir()?;
-            image_data = Some(create_url(&mut field, me.userId)?);
+        if metadata.is_some() {
+            image_data = Some(create_url(&mut field, owner)?);
             if let Some(url) = &image_data {
-                let tmp_path = format!("{}/{}/{}.tmp.{}", PATH,me.userId, &url.imgName, &url.imgExt);
-                parse_image(&mut field, &tmp_path).await?
+                parse_image(&mut field, storage.as_ref(), &url.tmpKey, &config.limits).await?
             }
         }
     }
     
-    let mut image_dim: (u32, u32) = (0, 0);
+    let mut record = None;
     if let Some(paths) = &image_data {
         if let Some(me) = metadata {
-            image_dim = crop_image(&paths, me.xAxis, me.yAxis, me.imgWidth, me.imgHeight, me.userId)?;
+            let processed = process_image(storage.as_ref(), &workers, paths, me.crop(), owner, &config).await;
+            if processed.is_err() {
+                discard(storage.as_ref(), &paths.tmpKey).await;
+            }
+            record = Some(processed?);
         }
     }
 
-    if image_data.is_none() {
-        return Err(Error::from("Could not save image"));
-    }
-
-    let image_data = image_data.unwrap();
+    let (record, pending, timings) = match record {
+        Some(processed) => processed,
+        None => return Err(Error::new(ErrorKind::InvalidRequest, "Could not save image").with_field("metadata", "metadata and image are required")),
+    };
+    catalog.put(&record).await?;
 
-    Ok(HttpResponse::Ok().json(UploadResponse {
-        imgName: image_data.imgName.clone(),
-        imgExt: image_data.imgExt.clone(),
-        imgMd: Some(image_dim.0),
-        imgSm: Some(image_dim.1),
-        imgLg: None,
-    }))
+    avif.spawn(storage, catalog, owner, record.image_id, pending);
+    Ok(HttpResponse::Ok()
+        .insert_header(("Server-Timing", timings.server_timing()))
+        .json(UploadResponse::new(&record)))
 }
 
 
-pub async fn update_image(mut payload: Multipart) -> Result<HttpResponse, Error> {
+pub async fn update_image(req: HttpRequest, config: web::Data<Config>, workers: web::Data<WorkerPool>, avif: web::Data<AvifEncoder>, storage: web::Data<dyn Storage>, catalog: web::Data<dyn ImageCatalog>, mut payload: Multipart) -> Result<HttpResponse, Error> {
+    let user = req.user_info()?;
+    check_request_size(&req, &config.limits)?;
 
     let mut image_data: Option<ImageProps> = None;
     let mut metadata: Option<RequestMetadataUpdate> = None;
+    let mut old_files = vec![];
 
     if let Some(mut field) = payload.try_next().await? {
-        metadata = parse_metadata_update(&mut field).await?;
+        metadata = parse_metadata(&mut field, &config.limits).await?;
     }
+    let owner = match &metadata {
+        Some(me) => {
+            me.validate()?;
+            let owner = user.owner(me.userId, "update_image")?;
+            old_files = me.old_files(storage.as_ref(), catalog.as_ref(), owner).await?;
+            owner
+        }
+        None => user.userId,
+    };
 
     if let Some(mut field) = payload.try_next().await? {
-        if let Some(me) = &metadata {
-            image_data = Some(create_url(&mut field, me.userId)?);
+        if metadata.is_some() {
+            image_data = Some(create_url(&mut field, owner)?);
             if let Some(url) = &image_data {
-                let tmp_path = format!("{}/{}/{}.tmp.{}", PATH,me.userId, &url.imgName, &url.imgExt);
-                parse_image(&mut field, &tmp_path).await?
+                parse_image(&mut field, storage.as_ref(), &url.tmpKey, &config.limits).await?
             }
         }
     }
     
-    let mut image_dim: (u32, u32) = (0, 0);
+    let mut record = None;
     if let Some(paths) = &image_data {
         if let Some(me) = &metadata {
-            image_dim = crop_image(&paths, me.xAxis, me.yAxis, me.imgWidth, me.imgHeight, me.userId)?;
+            let processed = process_image(storage.as_ref(), &workers, paths, me.crop(), owner, &config).await;
+            if processed.is_err() {
+                discard(storage.as_ref(), &paths.tmpKey).await;
+            }
+            record = Some(processed?);
         }
     }
 
-    if image_data.is_none() {
-        return Err(Error::from("Could not save image"));
+    let (md, (record, pending, timings)) = match (&metadata, record) {
+        (Some(md), Some(processed)) => (md, processed),
+        _ => return Err(Error::new(ErrorKind::InvalidRequest, "Could not save image").with_field("metadata", "metadata and image are required")),
+    };
+
+    // the new image only goes live once the old one is out of the way;
+    // until then a failure leaves the old one as it was
+    if let Err(e) = move_trash(storage.as_ref(), owner, &old_files).await {
+        discard_record(storage.as_ref(), &record).await;
+        return Err(e);
+    }
+    if let Err(e) = catalog.put(&record).await {
+        restore_trash(storage.as_ref(), owner, &old_files).await;
+        discard_record(storage.as_ref(), &record).await;
+        return Err(e);
+    }
+    md.trash_record(catalog.as_ref(), owner).await?;
+
+    avif.spawn(storage, catalog, owner, record.image_id, pending);
+    Ok(HttpResponse::Ok()
+        .insert_header(("Server-Timing", timings.server_timing()))
+        .json(UploadResponse::new(&record)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{ResizeFilter, Sharpen, Variants};
+    use actix_web::http::StatusCode;
+
+    /// The default `lg` (1920x1080), `md` (720x576) and `sm` (320x240).
+    fn profiles() -> Vec<VariantProfile> {
+        Variants::default().to_vec()
+    }
+
+    fn sizes(w: u32, h: u32) -> Vec<(u32, u32)> {
+        profiles().iter().map(|profile| variant_size(profile, 1, w, h)).collect()
+    }
+
+    #[test]
+    fn portrait_fits_the_height() {
+        assert_eq!(sizes(700, 3000), [(252, 1080), (134, 576), (56, 240)]);
+    }
+
+    #[test]
+    fn panorama_fits_the_width() {
+        assert_eq!(sizes(2000, 500), [(1920, 480), (720, 180), (320, 80)]);
+    }
+
+    #[test]
+    fn small_crops_are_not_upscaled() {
+        assert_eq!(sizes(300, 200), [(300, 200); 3]);
+        // fits `sm` exactly, one way
+        assert_eq!(sizes(320, 100), [(320, 100); 3]);
     }
 
-    let image_data = image_data.unwrap();
+    #[test]
+    fn fit_width_ignores_the_height() {
+        let mut md = profiles()[1].clone();
+        md.fit = FitMode::Width;
+        assert_eq!(variant_size(&md, 1, 700, 3000), (700, 3000));
+        assert_eq!(variant_size(&md, 1, 1440, 3000), (720, 1500));
+        assert_eq!(variant_size(&md, 2, 2880, 500), (1440, 250));
+    }
+
+    #[test]
+    fn rounds_to_the_nearest_pixel() {
+        // 1199 * 720 / 1600 = 539.55
+        assert_eq!(variant_size(&profiles()[1], 1, 1600, 1199), (720, 540));
+        // 700 * 1152 / 3000 = 268.8 at 2x
+        assert_eq!(variant_size(&profiles()[1], 2, 700, 3000), (269, 1152));
+        // never rounds down to nothing
+        assert_eq!(variant_size(&profiles()[2], 1, 10000, 1), (320, 1));
+    }
+
+    fn with_densities(densities: &[u32]) -> Vec<VariantProfile> {
+        let mut profiles = profiles();
+        for profile in &mut profiles {
+            profile.densities = densities.to_vec();
+        }
+        profiles
+    }
+
+    #[test]
+    fn plan_scales_from_twice_the_size() {
+        let profiles = with_densities(&[2, 3]);
+        let targets = plan(&profiles, 4000, 3000);
+        let expected = [
+            ("1920", (1440, 1080), Some(1), 1),
+            ("1920@2x", (2880, 2160), None, 0),
+            // as large as the crop, so never a base
+            ("1920@3x", (4000, 3000), None, 0),
+            // the first of the two 1440x1080 targets
+            ("720", (720, 540), Some(0), 2),
+            ("720@2x", (1440, 1080), Some(1), 1),
+            ("720@3x", (2160, 1620), None, 0),
+            ("320", (320, 240), Some(7), 3),
+            ("320@2x", (640, 480), Some(0), 2),
+            ("320@3x", (960, 720), Some(5), 1),
+        ];
+        let actual: Vec<_> = targets.iter().map(|t| (t.suffix(), t.size, t.base, t.depth)).collect();
+        let expected: Vec<_> = expected.into_iter().map(|(suffix, size, base, depth)| (suffix.to_owned(), size, base, depth)).collect();
+        assert_eq!(actual, expected);
+    }
 
-    if let Some(md) = &metadata {
-        md.move_trash()?;
+    #[test]
+    fn plan_only_shares_bases_between_alike_profiles() {
+        let mut profiles = with_densities(&[2, 3]);
+        profiles[1].filter = ResizeFilter::Triangle;
+        profiles[2].sharpen = Some(Sharpen { sigma: 0.5, threshold: 0 });
+        let targets = plan(&profiles, 4000, 3000);
+        let bases: Vec<_> = targets.iter().map(|t| (t.suffix(), t.base)).collect();
+        let expected = [
+            ("1920", Some(1)),
+            ("1920@2x", None),
+            ("1920@3x", None),
+            // no longer from `1920`, which uses another filter
+            ("720", Some(4)),
+            ("720@2x", None),
+            ("720@3x", None),
+            // `320` and `320@2x` only have each other
+            ("320", Some(7)),
+            ("320@2x", None),
+            ("320@3x", None),
+        ];
+        let expected: Vec<_> = expected.into_iter().map(|(suffix, base)| (suffix.to_owned(), base)).collect();
+        assert_eq!(bases, expected);
     }
 
-    Ok(HttpResponse::Ok().json(UploadResponse {
-        imgName: image_data.imgName.clone(),
-        imgExt: image_data.imgExt.clone(),
-        imgMd: Some(image_dim.0),
-        imgSm: Some(image_dim.1),
-        imgLg: None,
-    }))
+    #[test]
+    fn plan_skips_densities_the_crop_is_too_small_for() {
+        let profiles = with_densities(&[2, 3]);
+        let targets = plan(&profiles, 800, 600);
+        let suffixes: Vec<String> = targets.iter().map(Target::suffix).collect();
+        assert_eq!(suffixes, ["1920", "720", "720@2x", "320", "320@2x", "320@3x"]);
+        for profile in &profiles {
+            let sizes: Vec<_> = targets.iter().filter(|t| t.profile.name == profile.name).map(|t| t.size).collect();
+            let mut distinct = sizes.clone();
+            distinct.dedup();
+            assert_eq!(sizes, distinct, "{} repeats a size", profile.name);
+        }
+        // the crop itself is no base either
+        assert!(targets.iter().all(|t| t.base.is_none_or(|j| targets[j].size != (800, 600))));
+    }
+
+    #[test]
+    fn scale_all_makes_every_planned_size() {
+        let profiles = with_densities(&[2]);
+        let crop = image::RgbaImage::new(1600, 1200);
+        let targets = plan(&profiles, 1600, 1200);
+        let sizes: Vec<_> = scale_all(&crop, &targets, ResizeEngine::Fast).iter().map(|img| img.dimensions()).collect();
+        assert_eq!(sizes, targets.iter().map(|t| t.size).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn scale_all_falls_back_to_the_crop() {
+        let profiles = profiles();
+        let crop = image::RgbaImage::new(800, 600);
+        // the base of the first target is only scaled after it
+        let targets = [
+            Target { profile: &profiles[2], density: 1, size: (320, 240), base: Some(1), depth: 0 },
+            Target { profile: &profiles[1], density: 1, size: (720, 540), base: None, depth: 2 },
+        ];
+        let sizes: Vec<_> = scale_all(&crop, &targets, ResizeEngine::Fast).iter().map(|img| img.dimensions()).collect();
+        assert_eq!(sizes, [(320, 240), (720, 540)]);
+    }
+
+    const JPEG: &[u8] = b"\xFF\xD8\xFF\xE0\x00\x10JFIF\x00";
+    const PNG: &[u8] = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR";
+    const WEBP: &[u8] = b"RIFF\x24\x00\x00\x00WEBPVP8 ";
+    const GIF: &[u8] = b"GIF89a\x01\x00\x01\x00\x00\x00\x00";
+
+    fn sniff_status(data: &[u8], file_name: &str) -> StatusCode {
+        sniff_ext(data, file_name).unwrap_err().get_status()
+    }
+
+    #[test]
+    fn sniff_ext_goes_by_the_bytes() {
+        assert_eq!(sniff_ext(JPEG, "photo.jpg").unwrap(), "jpg");
+        assert_eq!(sniff_ext(JPEG, "my.photo.jpg").unwrap(), "jpg");
+        assert_eq!(sniff_ext(JPEG, "IMG_001.JPG").unwrap(), "jpg");
+        assert_eq!(sniff_ext(JPEG, "photo.jpeg").unwrap(), "jpg");
+        assert_eq!(sniff_ext(PNG, "screenshot.PNG").unwrap(), "png");
+        assert_eq!(sniff_ext(WEBP, "sticker.webp").unwrap(), "webp");
+        // names that say nothing about the format are not held against it
+        assert_eq!(sniff_ext(PNG, "screenshot").unwrap(), "png");
+        assert_eq!(sniff_ext(JPEG, "photo.jpg.download").unwrap(), "jpg");
+    }
+
+    #[test]
+    fn sniff_ext_refuses_misnamed_images() {
+        assert_eq!(sniff_status(PNG, "photo.jpg"), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(sniff_status(JPEG, "my.photo.webp"), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(sniff_ext(PNG, "photo.jpg").unwrap_err().get_message(), "CONTENT_MISMATCH");
+    }
+
+    #[test]
+    fn sniff_ext_refuses_other_content() {
+        for data in [GIF, b"not an image at all".as_slice(), b"".as_slice()] {
+            assert_eq!(sniff_status(data, "photo.jpg"), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+            assert_eq!(sniff_ext(data, "photo").unwrap_err().get_message(), "UNSUPPORTED_IMAGE");
+        }
+        assert_eq!(sniff_status(GIF, "anim.gif"), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    }
+
+    #[test]
+    fn decode_refuses_by_the_header_alone() {
+        /// A PNG announcing `width` x `height` pixels without any pixel
+        /// data, so that decoding it at all would fail differently.
+        fn header(width: u32, height: u32) -> Vec<u8> {
+            fn chunk(png: &mut Vec<u8>, kind: &[u8], data: &[u8]) {
+                png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+                let start = png.len();
+                png.extend_from_slice(kind);
+                png.extend_from_slice(data);
+                let crc = png[start..].iter().fold(!0u32, |crc, &byte| {
+                    (0..8).fold(crc ^ u32::from(byte), |crc, _| if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 })
+                });
+                png.extend_from_slice(&(!crc).to_be_bytes());
+            }
+            let mut png = PNG[..8].to_vec();
+            let mut ihdr = [width.to_be_bytes(), height.to_be_bytes()].concat();
+            ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+            chunk(&mut png, b"IHDR", &ihdr);
+            chunk(&mut png, b"IDAT", &[]);
+            png
+        }
+        let limits = LimitsConfig::default();
+        let refused = |png: &[u8], limits: &LimitsConfig| {
+            let e = decode(png, ImageFormat::Png, limits).unwrap_err();
+            assert_eq!(e.get_status(), StatusCode::UNPROCESSABLE_ENTITY);
+            e.get_message()
+        };
+
+        assert_eq!(refused(&header(100_000, 100_000), &limits), "image has too many pixels.");
+        assert_eq!(refused(&header(20_000, 1), &limits), "Image size exceeds limit");
+        assert_eq!(refused(&header(1, 20_000), &limits), "Image size exceeds limit");
+        // within the limits the missing pixel data is what fails
+        assert_eq!(refused(&header(100, 100), &limits), "unexpected end of file");
+    }
 }
+
@@ -0,0 +1,173 @@
+use crate::config::WorkersConfig;
+use crate::error::{Error, ErrorKind};
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use futures::channel::oneshot;
+use tokio::sync::Semaphore;
+
+/// Runs image decoding, cropping and encoding on dedicated threads, away
+/// from the async workers. At most `threads` jobs run at once and `queue`
+/// more wait for a thread; anything beyond that is refused with 503.
+pub struct WorkerPool {
+    pool: rayon::ThreadPool,
+    running: Arc<Semaphore>,
+    waiting: AtomicUsize,
+    max_waiting: usize,
+    retry_after: u64,
+}
+
+/// Counts a job as waiting until it gets a thread or its request goes away.
+struct Waiting<'a>(&'a AtomicUsize);
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl WorkerPool {
+    pub fn new(config: &WorkersConfig) -> anyhow::Result<Self> {
+        Self::named("lily-worker", config)
+    }
+
+    /// A pool whose threads are called `name-0`, `name-1`, ...
+    pub fn named(name: &'static str, config: &WorkersConfig) -> anyhow::Result<Self> {
+        let threads = config.threads();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(move |i| format!("{}-{}", name, i))
+            .build()?;
+        Ok(WorkerPool {
+            pool,
+            running: Arc::new(Semaphore::new(threads)),
+            waiting: AtomicUsize::new(0),
+            max_waiting: config.queue,
+            retry_after: config.retry_after,
+        })
+    }
+
+    /// Runs `job` on the pool and waits for its result. A panicking job
+    /// fails its request with 500 and leaves the pool intact.
+    pub async fn run<T, F>(&self, job: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> Result<T, Error> + Send + 'static,
+    {
+        let permit = match self.running.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                let ahead = self.waiting.fetch_add(1, Ordering::SeqCst);
+                let _waiting = Waiting(&self.waiting);
+                if ahead >= self.max_waiting {
+                    return Err(Error::new(ErrorKind::Unavailable, "image workers are busy.")
+                        .with_retry_after(self.retry_after));
+                }
+                self.running
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .map_err(|_| Error::from("image workers have stopped"))?
+            }
+        };
+        let (sender, receiver) = oneshot::channel();
+        self.pool.spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(job));
+            // the next job may start as soon as this one is done, whether
+            // or not its request is still waiting for it
+            drop(permit);
+            let _ = sender.send(result);
+        });
+        match receiver.await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(Error::from("image processing panicked")),
+            Err(_) => Err(Error::from("image worker went away")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use actix_web::http::{header, StatusCode};
+    use actix_web::{rt, ResponseError};
+
+    fn pool(queue: usize) -> Arc<WorkerPool> {
+        Arc::new(WorkerPool::new(&WorkersConfig { threads: 1, queue, retry_after: 7 }).unwrap())
+    }
+
+    /// Lets spawned requests run until `waiting` of them wait for a thread.
+    async fn until_waiting(pool: &WorkerPool, waiting: usize) {
+        while pool.waiting.load(Ordering::SeqCst) != waiting {
+            rt::task::yield_now().await;
+        }
+    }
+
+    #[actix_web::test]
+    async fn full_queue_is_503_with_retry_after() {
+        let pool = pool(1);
+        let (release, blocked) = mpsc::channel::<()>();
+        let running = rt::spawn({
+            let pool = pool.clone();
+            async move { pool.run(move || Ok(blocked.recv().is_ok())).await }
+        });
+        while pool.running.available_permits() != 0 {
+            rt::task::yield_now().await;
+        }
+        let queued = rt::spawn({
+            let pool = pool.clone();
+            async move { pool.run(|| Ok(true)).await }
+        });
+        until_waiting(&pool, 1).await;
+
+        let e = pool.run(|| Ok(true)).await.unwrap_err();
+        let res = e.error_response();
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers().get(header::RETRY_AFTER).unwrap(), "7");
+        // the refused job does not hold a place in the queue
+        assert_eq!(pool.waiting.load(Ordering::SeqCst), 1);
+
+        release.send(()).unwrap();
+        assert!(running.await.unwrap().unwrap());
+        assert!(queued.await.unwrap().unwrap());
+        assert_eq!(pool.waiting.load(Ordering::SeqCst), 0);
+        assert!(pool.run(|| Ok(true)).await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn abandoned_requests_leave_the_queue() {
+        let pool = pool(1);
+        let (release, blocked) = mpsc::channel::<()>();
+        let running = rt::spawn({
+            let pool = pool.clone();
+            async move { pool.run(move || Ok(blocked.recv().is_ok())).await }
+        });
+        while pool.running.available_permits() != 0 {
+            rt::task::yield_now().await;
+        }
+        let queued = rt::spawn({
+            let pool = pool.clone();
+            async move { pool.run(|| Ok(true)).await }
+        });
+        until_waiting(&pool, 1).await;
+        queued.abort();
+        until_waiting(&pool, 0).await;
+
+        release.send(()).unwrap();
+        assert!(running.await.unwrap().unwrap());
+    }
+
+    #[actix_web::test]
+    async fn panicking_job_fails_without_poisoning_the_pool() {
+        let pool = pool(0);
+        let e = pool.run::<(), _>(|| panic!("corrupt image")).await.unwrap_err();
+        assert_eq!(e.error_response().status(), StatusCode::INTERNAL_SERVER_ERROR);
+        // its thread and permit are back for the next job
+        assert_eq!(pool.running.available_permits(), 1);
+        assert_eq!(pool.run(|| Ok(2)).await.unwrap(), 2);
+        let e = pool.run::<(), _>(|| Err(Error::new(ErrorKind::UnprocessableImage, "bad"))).await.unwrap_err();
+        assert_eq!(e.get_status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}